@@ -1,3 +1,6 @@
+use crate::error::{Error, Result};
+use crate::hvqm::RecordType;
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum ADPCMFormat {
     Reset,
@@ -5,22 +8,42 @@ pub enum ADPCMFormat {
 }
 
 impl ADPCMFormat {
-    pub fn new(format: u32) -> Result<ADPCMFormat, ()> {
+    pub fn new(format: u32) -> Result<ADPCMFormat> {
         match format {
             0 => Ok(ADPCMFormat::Reset),
             1 => Ok(ADPCMFormat::Continue),
-            _ => Err(()),
+            _ => Err(Error::UnknownFormat { record_type: RecordType::Audio, format: format as u16 }),
+        }
+    }
+
+    /* Number of bytes holding `samples` samples of one channel */
+    pub fn encoded_size(self, samples: u32) -> usize {
+        match self {
+            _ if samples == 0 => 0,
+            ADPCMFormat::Reset => 2 + (samples as usize - 1).div_ceil(2),
+            ADPCMFormat::Continue => (samples as usize).div_ceil(2),
         }
     }
 }
 
+/*
+ * ChannelExpansion : Output layout of a decoded mono stream
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum ChannelExpansion {
+    #[default]
+    None,            /* One sample per decoded sample */
+    MonoToStereo,    /* Every sample is written twice, as a left/right pair */
+}
+
 /* ADPCM state information structure */
+#[derive(Copy, Clone, Default, Debug)]
 pub struct ADPCMstate {
     previous: i16,
     step_index: u8,
 }
 
-const D_0001D0: &'static [i32] = &[
+const D_0001D0: &[i32] = &[
     -1,
     -1,
     -1,
@@ -39,7 +62,7 @@ const D_0001D0: &'static [i32] = &[
     8,
 ];
 
-const D_000210: &'static [i32] = &[
+const D_000210: &[i32] = &[
     0x0007,
     0x0008,
     0x0009,
@@ -140,23 +163,51 @@ impl ADPCMstate {
         }
     }
 
-    pub fn adpcm_decode(&mut self, instream: &[u8], format: ADPCMFormat, samples: u32, ex_stereo: bool) -> Vec<i16> {
+    /* Last decoded sample */
+    pub fn previous(&self) -> i16 {
+        self.previous
+    }
+
+    pub fn step_index(&self) -> u8 {
+        self.step_index
+    }
+
+    /*
+     * Decodes `samples` samples of one channel.
+     * With `ChannelExpansion::MonoToStereo` twice as many values are returned.
+     */
+    pub fn adpcm_decode(&mut self, instream: &[u8], format: ADPCMFormat, samples: u32, expansion: ChannelExpansion) -> Result<Vec<i16>> {
         let mut var_t0: i32;
         let mut step_index: i32;
         let mut hi_nibble: bool;
         let mut in_offset = 0;
         let mut samples_left = samples;
 
+        let ex_stereo = expansion == ChannelExpansion::MonoToStereo;
         let mut outstream = Vec::new();
 
+        if samples == 0 {
+            return Ok(outstream);
+        }
+
+        let needed = format.encoded_size(samples);
+        if instream.len() < needed {
+            return Err(Error::Truncated { structure: "ADPCM data", offset: 0, needed, available: instream.len() });
+        }
+
         if format == ADPCMFormat::Reset {
             let temp_a0 = instream[in_offset];
             in_offset += 1;
             let t = instream[in_offset];
             in_offset += 1;
 
+            let step_index = t & 0x7F;
+            if step_index as usize >= D_000210.len() {
+                return Err(Error::InvalidStepIndex { value: step_index });
+            }
+
             self.previous = (((temp_a0 as u32) << 8) | ((t as u32) & 0x80)) as i16;
-            self.step_index = t & 0x7F;
+            self.step_index = step_index;
             outstream.push(self.previous);
             if ex_stereo {
                 outstream.push(self.previous);
@@ -178,34 +229,7 @@ impl ADPCMstate {
                 in_offset += 1;
             }
 
-            let temp_a0_2 = D_000210[step_index as usize];
-            let mut var_v1 = temp_a0_2 >> 3;
-            if (var_a1 & 1) != 0 {
-                var_v1 += temp_a0_2 >> 2;
-            }
-            if (var_a1 & 2) != 0 {
-                var_v1 += temp_a0_2 >> 1;
-            }
-            if (var_a1 & 4) != 0 {
-                var_v1 += temp_a0_2;
-            }
-            if (var_a1 & 8) != 0 {
-                var_v1 = -var_v1;
-            }
-
-            var_t0 += var_v1;
-            if var_t0 > i16::MAX as i32 {
-                var_t0 = i16::MAX as i32;
-            } else if var_t0 < i16::MIN as i32 {
-                var_t0 = i16::MIN as i32;
-            }
-
-            step_index += D_0001D0[var_a1 as usize];
-            if step_index < 0 {
-                step_index = 0;
-            } else if step_index >= D_000210.len() as i32 {
-                step_index = (D_000210.len() as i32) - 1;
-            }
+            (var_t0, step_index) = adpcm_step(var_t0, step_index, var_a1);
 
             outstream.push(var_t0 as i16);
             if ex_stereo {
@@ -219,7 +243,171 @@ impl ADPCMstate {
         self.previous = var_t0 as i16;
         self.step_index = step_index as u8;
 
+        Ok(outstream)
+    }
+
+    /*
+     * Encodes `samples` of one channel so that `adpcm_decode` with the same
+     * `format` and starting state returns the closest reachable samples.
+     * Both functions leave the state the same way, so consecutive records
+     * can be encoded with one state.
+     *
+     * A Reset record starts with the first sample rounded to 9 bits and the
+     * current step index; an odd number of nibbles is padded with a zero.
+     */
+    pub fn adpcm_encode(&mut self, samples: &[i16], format: ADPCMFormat) -> Vec<u8> {
+        let mut outstream = Vec::with_capacity(format.encoded_size(samples.len() as u32));
+        let mut samples = samples.iter();
+
+        if format == ADPCMFormat::Reset {
+            let Some(&first) = samples.next() else {
+                return outstream;
+            };
+
+            let rounded = (first as i32 + 0x40).min(i16::MAX as i32) & !0x7F;
+            self.previous = rounded as i16;
+            outstream.push((rounded >> 8) as u8);
+            outstream.push((rounded as u8 & 0x80) | self.step_index);
+        }
+
+        let mut value = self.previous as i32;
+        let mut step_index = self.step_index as i32;
+        let mut pending_nibble: Option<u8> = None;
+
+        for &sample in samples {
+            /* Every nibble is tried, the decoder's clamping makes a closed form inexact */
+            let (nibble, next_value, next_step_index) = (0..16u32)
+                .map(|nibble| {
+                    let (next_value, next_step_index) = adpcm_step(value, step_index, nibble);
+                    (nibble as u8, next_value, next_step_index)
+                })
+                .min_by_key(|&(_, next_value, _)| (next_value - sample as i32).abs())
+                .unwrap();
+
+            value = next_value;
+            step_index = next_step_index;
+
+            match pending_nibble.take() {
+                Some(hi_nibble) => outstream.push((hi_nibble << 4) | nibble),
+                None => pending_nibble = Some(nibble),
+            }
+        }
+
+        if let Some(hi_nibble) = pending_nibble {
+            outstream.push(hi_nibble << 4);
+        }
+
+        self.previous = value as i16;
+        self.step_index = step_index as u8;
+
         outstream
     }
+}
+
+/*
+ * Applies one 4-bit code to the predicted value and step index
+ */
+fn adpcm_step(value: i32, step_index: i32, nibble: u32) -> (i32, i32) {
+    let step = D_000210[step_index as usize];
+    let mut delta = step >> 3;
+    if (nibble & 1) != 0 {
+        delta += step >> 2;
+    }
+    if (nibble & 2) != 0 {
+        delta += step >> 1;
+    }
+    if (nibble & 4) != 0 {
+        delta += step;
+    }
+    if (nibble & 8) != 0 {
+        delta = -delta;
+    }
+
+    let value = (value + delta).clamp(i16::MIN as i32, i16::MAX as i32);
+    let step_index = (step_index + D_0001D0[nibble as usize]).clamp(0, D_000210.len() as i32 - 1);
+    (value, step_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_rejects_step_index_past_table() {
+        let mut state = ADPCMstate::new();
+        let last = D_000210.len() as u8 - 1;
+
+        assert!(state.adpcm_decode(&[0x12, last, 0x00], ADPCMFormat::Reset, 3, ChannelExpansion::None).is_ok());
+        assert!(matches!(
+            state.adpcm_decode(&[0x12, 0x7F, 0x00], ADPCMFormat::Reset, 3, ChannelExpansion::None),
+            Err(Error::InvalidStepIndex { value: 0x7F })
+        ));
+    }
 
+    /*
+     * Encodes `records` (format, samples) with one state and decodes them
+     * with another, checking the data size and that both states agree
+     */
+    fn round_trip(records: &[(ADPCMFormat, &[i16])]) -> Vec<i16> {
+        let mut encoder = ADPCMstate::new();
+        let mut decoder = ADPCMstate::new();
+        let mut decoded = Vec::new();
+
+        for &(format, samples) in records {
+            let data = encoder.adpcm_encode(samples, format);
+            assert_eq!(data.len(), format.encoded_size(samples.len() as u32));
+
+            let record = decoder.adpcm_decode(&data, format, samples.len() as u32, ChannelExpansion::None).unwrap();
+            assert_eq!(record.len(), samples.len());
+            assert_eq!((decoder.previous(), decoder.step_index()), (encoder.previous(), encoder.step_index()));
+            decoded.extend(record);
+        }
+        decoded
+    }
+
+    fn max_error(input: &[i16], decoded: &[i16]) -> i32 {
+        input.iter().zip(decoded).map(|(&a, &b)| (a as i32 - b as i32).abs()).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn round_trip_follows_input() {
+        let sine: Vec<i16> = (0..2000).map(|i| ((i as f64 * 0.05).sin() * 12000.0) as i16).collect();
+        let decoded = round_trip(&[
+            (ADPCMFormat::Reset, &sine[..501]),
+            (ADPCMFormat::Continue, &sine[501..1000]),
+            (ADPCMFormat::Reset, &sine[1000..1333]),
+            (ADPCMFormat::Continue, &sine[1333..]),
+        ]);
+
+        /* The step size needs a few samples to adapt after the first record starts */
+        assert!(max_error(&sine[20..], &decoded[20..]) < 300);
+        let mean_error = sine.iter().zip(&decoded).map(|(&a, &b)| (a as i64 - b as i64).abs()).sum::<i64>() / sine.len() as i64;
+        assert!(mean_error < 100);
+    }
+
+    #[test]
+    fn round_trip_odd_sample_counts() {
+        let samples: Vec<i16> = (0..64).map(|i| (i * 311 % 4096) as i16 - 2048).collect();
+
+        for count in [1, 2, 3, 5, 7] {
+            round_trip(&[
+                (ADPCMFormat::Reset, &samples[..count]),
+                (ADPCMFormat::Continue, &samples[count..2 * count]),
+                (ADPCMFormat::Continue, &samples[2 * count..3 * count + 1]),
+            ]);
+        }
+    }
+
+    #[test]
+    fn round_trip_clamps_extremes() {
+        let square: Vec<i16> = (0..400).map(|i| if (i / 50) % 2 == 0 { i16::MAX } else { i16::MIN }).collect();
+        let decoded = round_trip(&[(ADPCMFormat::Reset, &square[..201]), (ADPCMFormat::Continue, &square[201..])]);
+
+        assert!(decoded.contains(&i16::MAX));
+        assert!(decoded.contains(&i16::MIN));
+        /* Each half period is long enough for the decoder to reach the rail */
+        for period in square.chunks(50).zip(decoded.chunks(50)) {
+            assert_eq!(period.1.last(), period.0.last());
+        }
+    }
 }
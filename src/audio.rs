@@ -0,0 +1,158 @@
+use crate::adpcm::{ADPCMstate, ChannelExpansion};
+use crate::error::{Error, Result};
+use crate::hvqm::{DataFormat, HVQM2AudioHeader, HVQM2Header};
+
+/* Decoded samples are always 16-bit */
+pub const OUTPUT_SAMPLE_BITS: u16 = 16;
+
+/*
+ * AudioDecoder : Decodes the ADPCM audio records of a file into 16-bit
+ * samples
+ *
+ * Only mono audio is decoded: how the SDK lays out the channels of a
+ * stereo record is not known (no SDK source or stereo file was available),
+ * so stereo files are rejected rather than decoded with a guessed layout.
+ */
+pub struct AudioDecoder {
+    states: Vec<ADPCMstate>,    /* One per channel */
+    expansion: ChannelExpansion,
+}
+
+impl AudioDecoder {
+    pub fn new(header: &HVQM2Header) -> Result<AudioDecoder> {
+        check_audio_header(header)?;
+
+        Ok(AudioDecoder {
+            states: (0..header.channels).map(|_| ADPCMstate::new()).collect(),
+            expansion: ChannelExpansion::None,
+        })
+    }
+
+    /* Channels stored in the file */
+    pub fn channels(&self) -> u16 {
+        self.states.len() as u16
+    }
+
+    /* Channels of the decoded samples, after any expansion */
+    pub fn output_channels(&self) -> u16 {
+        match self.expansion {
+            ChannelExpansion::MonoToStereo if self.states.len() == 1 => 2,
+            _ => self.channels(),
+        }
+    }
+
+    pub fn expansion(&self) -> ChannelExpansion {
+        self.expansion
+    }
+
+    /* Selects how the mono samples are output (as is by default) */
+    pub fn set_expansion(&mut self, expansion: ChannelExpansion) {
+        self.expansion = expansion;
+    }
+
+    /* ADPCM state of every channel */
+    pub fn states(&self) -> &[ADPCMstate] {
+        &self.states
+    }
+
+    /* Restores states saved with `states`, e.g. to resume decoding at a key frame */
+    pub fn set_states(&mut self, states: &[ADPCMstate]) {
+        self.states.copy_from_slice(states);
+    }
+
+    /*
+     * Decodes one audio record of `format` (AudioKeyframe or AudioPredict)
+     * holding `samples` samples. With `ChannelExpansion::MonoToStereo`
+     * every sample is output twice, for the left and right channels.
+     */
+    pub fn decode(&mut self, data: &[u8], format: DataFormat, samples: u32) -> Result<Vec<i16>> {
+        if !matches!(format, DataFormat::AudioKeyframe | DataFormat::AudioPredict) {
+            return Err(Error::UnexpectedFormat { expected: "audio", found: format });
+        }
+
+        self.states[0].adpcm_decode(data, format.to_adpcm_format()?, samples, self.expansion)
+    }
+}
+
+/*
+ * AudioEncoder : Encodes mono 16-bit samples into audio records laid out
+ * the way `AudioDecoder` reads them
+ */
+pub struct AudioEncoder {
+    states: Vec<ADPCMstate>,    /* One per channel */
+}
+
+impl AudioEncoder {
+    pub fn new(header: &HVQM2Header) -> Result<AudioEncoder> {
+        check_audio_header(header)?;
+
+        Ok(AudioEncoder {
+            states: (0..header.channels).map(|_| ADPCMstate::new()).collect(),
+        })
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.states.len() as u16
+    }
+
+    /*
+     * Encodes the data of an audio record of `format` (AudioKeyframe or
+     * AudioPredict), starting with its HVQM2AudioHeader
+     */
+    pub fn encode_record(&mut self, format: DataFormat, samples: &[i16]) -> Result<Vec<u8>> {
+        if !matches!(format, DataFormat::AudioKeyframe | DataFormat::AudioPredict) {
+            return Err(Error::UnexpectedFormat { expected: "audio", found: format });
+        }
+
+        let mut data = HVQM2AudioHeader { samples: samples.len() as u32 }.to_bytes().to_vec();
+        data.extend(self.states[0].adpcm_encode(samples, format.to_adpcm_format()?));
+        Ok(data)
+    }
+}
+
+/* Checks that the audio format, channels (mono only, see `AudioDecoder`) and sample size of `header` can be coded */
+fn check_audio_header(header: &HVQM2Header) -> Result<()> {
+    let format = header.audio_data_format()?;
+
+    if header.channels != 1 {
+        return Err(Error::UnsupportedChannels { channels: header.channels });
+    }
+    if header.sample_bits != 16 {
+        return Err(Error::UnsupportedSampleBits { format, sample_bits: header.sample_bits });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_mono_is_supported() {
+        let mut header = HVQM2Header::for_video(16, 16, 2, 2, 4, 33333, 1);
+        assert!(AudioDecoder::new(&header).is_ok());
+        for channels in [0, 2, 6] {
+            header.channels = channels;
+            assert!(matches!(AudioDecoder::new(&header), Err(Error::UnsupportedChannels { channels: found }) if found == channels));
+            assert!(matches!(AudioEncoder::new(&header), Err(Error::UnsupportedChannels { .. })));
+        }
+    }
+
+    #[test]
+    fn mono_to_stereo_doubles_every_sample() {
+        let header = HVQM2Header::for_video(16, 16, 2, 2, 4, 33333, 1);
+        let samples: Vec<i16> = (0..50).map(|i| (i * 997 % 4000 - 2000) as i16 * 8).collect();
+        let data = AudioEncoder::new(&header).unwrap().encode_record(DataFormat::AudioKeyframe, &samples).unwrap();
+        let data = &data[HVQM2AudioHeader::SIZE..];
+
+        let mut mono = AudioDecoder::new(&header).unwrap();
+        let mut stereo = AudioDecoder::new(&header).unwrap();
+        stereo.set_expansion(ChannelExpansion::MonoToStereo);
+        assert_eq!(stereo.output_channels(), 2);
+
+        let mono = mono.decode(data, DataFormat::AudioKeyframe, 50).unwrap();
+        let stereo = stereo.decode(data, DataFormat::AudioKeyframe, 50).unwrap();
+        assert_eq!(stereo, mono.iter().flat_map(|&sample| [sample, sample]).collect::<Vec<_>>());
+    }
+}
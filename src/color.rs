@@ -0,0 +1,180 @@
+use crate::video::Picture;
+
+/*
+ * PixelFormat : Output framebuffer pixel format
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    Rgba5551,    /* 16-bit RGBA, big-endian */
+    Rgba8888,    /* 32-bit RGBA, one byte per component */
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgba5551 => 2,
+            PixelFormat::Rgba8888 => 4,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            PixelFormat::Rgba5551 => "16-bit RGBA",
+            PixelFormat::Rgba8888 => "32-bit RGBA",
+        }
+    }
+}
+
+/*
+ * YUV to RGB conversion coefficients, 12-bit fixed point
+ *
+ * These are the standard BT.601 full range coefficients, with products
+ * rounded down. They are a stand-in: the HVQM2 library's own conversion
+ * (coefficients, rounding and 5-bit packing) was not available, and
+ * neither were framebuffer captures from hardware or the SDK to take
+ * reference values from. Colors can differ from what the library shows.
+ * The tests below only check that the code computes BT.601, not that
+ * BT.601 is what the library uses.
+ */
+const V_TO_R: i32 = 0x166E;    /* 1.402 */
+const U_TO_G: i32 = 0x0581;    /* 0.344 */
+const V_TO_G: i32 = 0x0B6D;    /* 0.714 */
+const U_TO_B: i32 = 0x1C5A;    /* 1.772 */
+
+/*
+ * Converts one YUV sample (chroma centered on 128) to 8-bit RGB
+ */
+pub fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let y = y as i32;
+    let u = u as i32 - 0x80;
+    let v = v as i32 - 0x80;
+
+    let r = y + ((V_TO_R * v) >> 12);
+    let g = y - ((U_TO_G * u) >> 12) - ((V_TO_G * v) >> 12);
+    let b = y + ((U_TO_B * u) >> 12);
+
+    [r.clamp(0, 255) as u8, g.clamp(0, 255) as u8, b.clamp(0, 255) as u8]
+}
+
+/*
+ * Converts 8-bit RGB to one YUV sample (chroma centered on 128), the
+ * inverse of `yuv_to_rgb` (BT.601, full range)
+ */
+pub fn rgb_to_yuv(rgb: [u8; 3]) -> [u8; 3] {
+    let [r, g, b] = rgb.map(|value| value as i32);
+
+    /* 16-bit fixed point: 0.299, 0.587, 0.114 / 0.168736, 0.331264, 0.5 / 0.5, 0.418688, 0.081312 */
+    let y = (19595 * r + 38470 * g + 7471 * b + 0x8000) >> 16;
+    let u = ((-11058 * r - 21710 * g + 32768 * b + 0x8000) >> 16) + 0x80;
+    let v = ((32768 * r - 27439 * g - 5329 * b + 0x8000) >> 16) + 0x80;
+
+    [y.clamp(0, 255) as u8, u.clamp(0, 255) as u8, v.clamp(0, 255) as u8]
+}
+
+/*
+ * Packs 8-bit RGB into the N64 16-bit framebuffer format (5 bits per
+ * component, truncated, alpha bit always set). Truncation is an
+ * assumption, see the conversion coefficients above.
+ */
+pub fn rgb_to_rgba5551(rgb: [u8; 3]) -> u16 {
+    let [r, g, b] = rgb;
+
+    ((r as u16 >> 3) << 11) | ((g as u16 >> 3) << 6) | ((b as u16 >> 3) << 1) | 1
+}
+
+pub fn yuv_to_rgba5551(y: u8, u: u8, v: u8) -> u16 {
+    rgb_to_rgba5551(yuv_to_rgb(y, u, v))
+}
+
+/* Expands RGBA5551 back to 8-bit RGB, replicating the top bits */
+pub fn rgba5551_to_rgb(pixel: u16) -> [u8; 3] {
+    let expand = |value: u16| {
+        let value = (value & 0x1F) as u8;
+        (value << 3) | (value >> 2)
+    };
+
+    [expand(pixel >> 11), expand(pixel >> 6), expand(pixel >> 1)]
+}
+
+/*
+ * Converts the displayed area of a picture to packed 8-bit RGB as seen
+ * through `format`: the 16-bit format keeps its 5-bit quantisation.
+ * Chroma samples are repeated over the pixels they cover, no interpolation.
+ */
+pub fn picture_to_rgb8(picture: &Picture, format: PixelFormat) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(picture.width * picture.height * 3);
+    match format {
+        PixelFormat::Rgba5551 => for_each_pixel(picture, |y, u, v| rgb.extend(rgba5551_to_rgb(yuv_to_rgba5551(y, u, v)))),
+        PixelFormat::Rgba8888 => for_each_pixel(picture, |y, u, v| rgb.extend(yuv_to_rgb(y, u, v))),
+    }
+    rgb
+}
+
+/*
+ * Converts the displayed area of a picture to the framebuffer layout of
+ * `format`, in N64 (big-endian) byte order.
+ */
+pub fn picture_to_framebuffer(picture: &Picture, format: PixelFormat) -> Vec<u8> {
+    let mut framebuffer = Vec::with_capacity(picture.width * picture.height * format.bytes_per_pixel());
+    match format {
+        PixelFormat::Rgba5551 => for_each_pixel(picture, |y, u, v| framebuffer.extend(yuv_to_rgba5551(y, u, v).to_be_bytes())),
+        PixelFormat::Rgba8888 => for_each_pixel(picture, |y, u, v| {
+            framebuffer.extend(yuv_to_rgb(y, u, v));
+            framebuffer.push(0xFF);
+        }),
+    }
+    framebuffer
+}
+
+fn for_each_pixel(picture: &Picture, mut f: impl FnMut(u8, u8, u8)) {
+    for y in 0..picture.height {
+        for x in 0..picture.width {
+            let cx = x / picture.h_sampling_rate;
+            let cy = y / picture.v_sampling_rate;
+            f(picture.y.get(x, y), picture.u.get(cx, cy), picture.v.get(cx, cy));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* BT.601 full range in floating point, clamped, rounded down like the fixed point products */
+    fn bt601(y: u8, u: u8, v: u8) -> [f64; 3] {
+        let (y, u, v) = (y as f64, u as f64 - 128.0, v as f64 - 128.0);
+        [y + 1.402 * v, y - 0.344136 * u - 0.714136 * v, y + 1.772 * u].map(|value| value.clamp(0.0, 255.0))
+    }
+
+    #[test]
+    fn yuv_to_rgb_computes_bt601() {
+        for y in (0..=255).step_by(5) {
+            for u in (0..=255).step_by(5) {
+                for v in (0..=255).step_by(5) {
+                    let rgb = yuv_to_rgb(y, u, v);
+                    /* Truncated coefficients and floored products: red and blue can be about 1 off, green (two products) about 2 */
+                    for ((component, expected), tolerance) in rgb.iter().zip(bt601(y, u, v)).zip([1.5, 2.5, 1.5]) {
+                        assert!((*component as f64 - expected).abs() < tolerance, "yuv {y} {u} {v}: {rgb:?} vs {:?}", bt601(y, u, v));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn yuv_to_rgba5551_values() {
+        assert_eq!(yuv_to_rgba5551(0, 0x80, 0x80), 0x0001);
+        assert_eq!(yuv_to_rgba5551(0xFF, 0x80, 0x80), 0xFFFF);
+        assert_eq!(yuv_to_rgba5551(0x80, 0x80, 0x80), 0x8421);
+        /* Saturated red: rgb 254, 1, 0 (blue clamped from -1) */
+        assert_eq!(yuv_to_rgb(76, 85, 255), [254, 1, 0]);
+        assert_eq!(yuv_to_rgba5551(76, 85, 255), 0xF801);
+    }
+
+    #[test]
+    fn rgba5551_expansion_round_trips() {
+        for pixel in (0..=0xFFFFu16).filter(|pixel| pixel & 1 == 1) {
+            assert_eq!(rgb_to_rgba5551(rgba5551_to_rgb(pixel)), pixel);
+        }
+    }
+}
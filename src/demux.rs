@@ -0,0 +1,349 @@
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use crate::error::{Error, Result};
+use crate::hvqm::{HVQM2AudioHeader, HVQM2Header, HVQM2Record, Record};
+
+/*
+ * DemuxedRecord : One record read from the stream
+ */
+pub struct DemuxedRecord {
+    pub index: u32,          /* Position of the record in the file */
+    pub offset: u64,         /* Offset of the record header from the start of the file header */
+    pub header: HVQM2Record,
+    pub data: Vec<u8>,       /* Record data (excluding the record header) */
+}
+
+impl DemuxedRecord {
+    /* Typed view of the record, borrowing its data */
+    pub fn record(&self) -> Result<Record<'_>> {
+        Record::parse(&self.header, &self.data)
+    }
+}
+
+/*
+ * Demuxer : Reads the file header, then one record at a time
+ *
+ * Only the current record is kept in memory, so any `Read` source works
+ * (files, pipes); `Read + Seek` sources can also start in the middle of a
+ * larger image. Zero padding after the last record ends the records, like
+ * with `Records`.
+ */
+pub struct Demuxer<R> {
+    reader: R,
+    header: HVQM2Header,
+    base: u64,              /* Stream position of the file header */
+    offset: u64,            /* Offset of the next record, relative to `base` */
+    limit: Option<u64>,     /* Stop reading records at this offset, relative to `base` */
+    record_index: u32,
+    failed: bool,           /* The stream is not read past an error */
+    padding: u64,           /* Zero bytes found after the last record */
+}
+
+impl<R: Read> Demuxer<R> {
+    /*
+     * Reads the file header from the current position of `reader`.
+     * Records are then read until the end of the stream.
+     * `seek_to_record` and `rewind` take that position to be 0: use
+     * `at_current_position` to seek in a stream that starts elsewhere.
+     */
+    pub fn new(mut reader: R) -> Result<Demuxer<R>> {
+        let mut buf = [0u8; HVQM2Header::SIZE];
+        let read = read_up_to(&mut reader, &mut buf)?;
+        let header = HVQM2Header::parse(&buf[..read])?;
+        header.check_magic()?;
+
+        Ok(Demuxer {
+            reader,
+            header,
+            base: 0,
+            offset: HVQM2Header::SIZE as u64,
+            limit: None,
+            record_index: 0,
+            failed: false,
+            padding: 0,
+        })
+    }
+
+    pub fn header(&self) -> &HVQM2Header {
+        &self.header
+    }
+
+    /*
+     * Stops reading records at `limit` bytes from the start of the file
+     * header, e.g. its `file_size`, instead of at the end of the stream
+     */
+    pub fn set_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit;
+    }
+
+    /* Stream position of the file header, record offsets are relative to it */
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /* Length of the zero padding after the last record, once it was reached */
+    pub fn padding(&self) -> u64 {
+        self.padding
+    }
+
+    /* Offset of the next record relative to the start of the file header */
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /*
+     * Reads the next record, or returns `None` at the end of the stream.
+     * Errors are tagged with the index and offset of the failing record.
+     */
+    pub fn next_record(&mut self) -> Result<Option<DemuxedRecord>> {
+        if self.failed || self.limit.is_some_and(|limit| self.offset >= limit) {
+            return Ok(None);
+        }
+
+        let index = self.record_index;
+        let offset = self.offset;
+        let record = self.read_record().map_err(|err| {
+            self.failed = true;
+            err.in_record(index, self.base + offset)
+        })?;
+
+        if record.is_some() {
+            self.record_index += 1;
+        }
+        Ok(record)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /* Bytes left before the limit */
+    fn remaining(&self) -> u64 {
+        match self.limit {
+            Some(limit) => limit.saturating_sub(self.offset),
+            None => u64::MAX,
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<DemuxedRecord>> {
+        let mut buf = [0u8; HVQM2Record::SIZE];
+        let remaining = self.remaining();
+        let read = read_up_to(&mut (&mut self.reader).take(remaining), &mut buf)?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        if buf[..read].iter().all(|&b| b == 0) {
+            return self.read_padding(read as u64);
+        }
+
+        let header = HVQM2Record::parse(&buf[..read])?;
+        header.data_format()?;
+
+        /* Grows with what is actually read, a corrupted size can't allocate gigabytes */
+        let mut data = Vec::new();
+        (&mut self.reader).take(header.size as u64).read_to_end(&mut data)?;
+        if data.len() < header.size as usize {
+            return Err(Error::Truncated {
+                structure: "record data",
+                offset: self.offset + HVQM2Record::SIZE as u64,
+                needed: header.size as usize,
+                available: data.len(),
+            });
+        }
+
+        let record = DemuxedRecord {
+            index: self.record_index,
+            offset: self.offset,
+            header,
+            data,
+        };
+        self.offset += (HVQM2Record::SIZE + record.data.len()) as u64;
+        Ok(Some(record))
+    }
+
+    /*
+     * Reads on after `read` zero bytes where a record header should be:
+     * zeros up to the end of the stream (or the limit) are padding. Anything
+     * else makes them an empty audio record, which is missing its audio
+     * header.
+     */
+    fn read_padding(&mut self, read: u64) -> Result<Option<DemuxedRecord>> {
+        let remaining = self.remaining().saturating_sub(read);
+        let mut reader = (&mut self.reader).take(remaining);
+        let mut buf = [0u8; 4096];
+        let mut padding = read;
+        loop {
+            let n = read_up_to(&mut reader, &mut buf)?;
+            if buf[..n].iter().any(|&b| b != 0) {
+                return Err(Error::Truncated {
+                    structure: "HVQM2AudioHeader",
+                    offset: self.offset + HVQM2Record::SIZE as u64,
+                    needed: HVQM2AudioHeader::SIZE,
+                    available: 0,
+                });
+            }
+            padding += n as u64;
+            if n < buf.len() {
+                break;
+            }
+        }
+
+        self.padding = padding;
+        self.offset += padding;
+        Ok(None)
+    }
+}
+
+impl<R: Read + Seek> Demuxer<R> {
+    /*
+     * Opens an HVQM2 file embedded at `offset` of `reader` (e.g. inside a
+     * ROM image). Reading stops after the header's `file_size` bytes.
+     */
+    pub fn at_offset(mut reader: R, offset: u64) -> Result<Demuxer<R>> {
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let mut demuxer = Demuxer::new(reader)?;
+        demuxer.base = offset;
+        demuxer.limit = Some(demuxer.header.file_size as u64);
+        Ok(demuxer)
+    }
+
+    /*
+     * Like `new`, but remembers the current position of `reader` as the
+     * start of the file header, for `seek_to_record` and `rewind`
+     */
+    pub fn at_current_position(mut reader: R) -> Result<Demuxer<R>> {
+        let base = reader.stream_position()?;
+
+        let mut demuxer = Demuxer::new(reader)?;
+        demuxer.base = base;
+        Ok(demuxer)
+    }
+
+    /*
+     * Continues reading at the record `index` whose header is at `offset`
+     * from the start of the file header (e.g. from a `KeyframeIndex`)
+     */
+    pub fn seek_to_record(&mut self, offset: u64, index: u32) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(self.base + offset))?;
+        self.offset = offset;
+        self.record_index = index;
+        self.failed = false;
+        Ok(())
+    }
+
+    /* Goes back to the first record */
+    pub fn rewind(&mut self) -> Result<()> {
+        self.seek_to_record(HVQM2Header::SIZE as u64, 0)
+    }
+}
+
+impl<R: Read> Iterator for Demuxer<R> {
+    type Item = Result<DemuxedRecord>;
+
+    fn next(&mut self) -> Option<Result<DemuxedRecord>> {
+        self.next_record().transpose()
+    }
+}
+
+/*
+ * Like `read_exact`, but returns how much was read when the stream ends early
+ */
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::hvqm::{DataFormat, HVQM2AudioHeader};
+    use crate::mux::HVQM2Writer;
+
+    #[test]
+    fn rewind_returns_to_the_current_position() {
+        let header = HVQM2Header::for_video(16, 16, 2, 2, 4, 33333, 1);
+        let mut image = vec![0xAA; 100];
+        let mut writer = HVQM2Writer::new(&mut image, &header).unwrap();
+        for samples in [3, 5] {
+            let mut data = HVQM2AudioHeader { samples }.to_bytes().to_vec();
+            data.extend([0x12, 0x34, 0x56, 0x78]);
+            writer.write_record(DataFormat::AudioKeyframe, &data).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = Cursor::new(image);
+        reader.set_position(100);
+        let mut demuxer = Demuxer::at_current_position(reader).unwrap();
+        let offsets = |demuxer: &mut Demuxer<_>| demuxer.map(|record| record.unwrap().offset).collect::<Vec<_>>();
+
+        let first = offsets(&mut demuxer);
+        assert_eq!(first, [HVQM2Header::SIZE as u64, HVQM2Header::SIZE as u64 + 16]);
+        demuxer.rewind().unwrap();
+        assert_eq!(offsets(&mut demuxer), first);
+    }
+
+    /* Header and two audio records, as in `rewind_returns_to_the_current_position` */
+    fn file() -> Vec<u8> {
+        let header = HVQM2Header::for_video(16, 16, 2, 2, 4, 33333, 1);
+        let mut writer = HVQM2Writer::new(Cursor::new(Vec::new()), &header).unwrap();
+        for samples in [3, 5] {
+            let mut data = HVQM2AudioHeader { samples }.to_bytes().to_vec();
+            data.extend([0x12, 0x34, 0x56, 0x78]);
+            writer.write_record(DataFormat::AudioKeyframe, &data).unwrap();
+        }
+        writer.finish_with_summary().unwrap().into_inner()
+    }
+
+    #[test]
+    fn zero_padding_ends_the_records() {
+        let file = file();
+        for padding in [3, 8, 64, 5000] {
+            let mut padded = file.clone();
+            padded.resize(file.len() + padding, 0);
+
+            let mut demuxer = Demuxer::new(Cursor::new(&padded)).unwrap();
+            assert_eq!((&mut demuxer).map(|record| record.unwrap().index).collect::<Vec<_>>(), [0, 1]);
+            assert_eq!(demuxer.padding(), padding as u64);
+
+            /* Embedded with a file_size that counts the padding, followed by something else */
+            let mut image = padded.clone();
+            image[0x10..0x14].copy_from_slice(&(padded.len() as u32).to_be_bytes());
+            image.extend([0xFF; 16]);
+            let mut demuxer = Demuxer::at_offset(Cursor::new(&image), 0).unwrap();
+            assert_eq!((&mut demuxer).map(|record| record.unwrap().index).collect::<Vec<_>>(), [0, 1]);
+            assert_eq!(demuxer.padding(), padding as u64);
+        }
+    }
+
+    #[test]
+    fn zeros_followed_by_data_are_an_error() {
+        let mut file = file();
+        let end = file.len() as u64;
+        file.extend([0; 20]);
+        file.push(1);
+
+        let mut demuxer = Demuxer::new(Cursor::new(&file)).unwrap();
+        assert!(demuxer.next_record().unwrap().is_some());
+        assert!(demuxer.next_record().unwrap().is_some());
+        match demuxer.next_record() {
+            Err(Error::InRecord { index: 2, offset, source }) => {
+                assert_eq!(offset, end);
+                assert!(matches!(*source, Error::Truncated { structure: "HVQM2AudioHeader", .. }));
+            },
+            _ => panic!("zeros followed by data read as padding"),
+        }
+        assert!(demuxer.next_record().unwrap().is_none());
+    }
+}
@@ -0,0 +1,184 @@
+use std::io::{Read, Seek, Write};
+
+use crate::audio::AudioEncoder;
+use crate::error::{Error, Result};
+use crate::hvqm::{HVQM2AudioHeader, HVQM2Header, HVQM2Record, Record, Records};
+use crate::mux::HVQM2Writer;
+
+/*
+ * AudioTrack : Interleaved 16-bit samples
+ */
+pub struct AudioTrack {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Vec<i16>,
+}
+
+impl AudioTrack {
+    /* Reads a WAV file, converting any integer or float sample size to 16 bits */
+    pub fn read_wav<R: Read>(reader: R) -> Result<AudioTrack> {
+        let reader = hound::WavReader::new(reader)?;
+        let spec = reader.spec();
+
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => reader.into_samples::<f32>()
+                .map(|sample| Ok((sample? * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16))
+                .collect::<Result<Vec<i16>>>()?,
+            hound::SampleFormat::Int => {
+                let bits = spec.bits_per_sample as u32;
+                reader.into_samples::<i32>()
+                    .map(|sample| Ok((if bits > 16 { sample? >> (bits - 16) } else { sample? << (16 - bits) }) as i16))
+                    .collect::<Result<Vec<i16>>>()?
+            },
+        };
+
+        Ok(AudioTrack {
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+            samples,
+        })
+    }
+
+    /* Number of samples per channel */
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    /*
+     * Converts to `channels` channels: stereo is mixed down to mono, mono
+     * is copied to both sides of stereo
+     */
+    pub fn with_channels(&self, channels: u16) -> Result<AudioTrack> {
+        let samples = match (self.channels, channels) {
+            (2, 1) => self.samples.chunks_exact(2).map(|pair| ((pair[0] as i32 + pair[1] as i32) / 2) as i16).collect(),
+            (1, 2) => self.samples.iter().flat_map(|&sample| [sample, sample]).collect(),
+            (from, to) if from == to => self.samples.clone(),
+            (from, _) => return Err(Error::UnsupportedChannels { channels: from.min(u8::MAX as u16) as u8 }),
+        };
+
+        Ok(AudioTrack {
+            channels,
+            sample_rate: self.sample_rate,
+            samples,
+        })
+    }
+}
+
+/*
+ * Writes a copy of the file in `buf` to `output` with the audio records
+ * re-encoded from `track`.
+ *
+ * The audio records keep their position among the video records and their
+ * AudioKeyframe/AudioPredict format. Their sample counts are the original
+ * ones scaled to the sample rate of `track`, so the audio stays in sync;
+ * `track` is converted to the file's channel count, cut or padded with
+ * silence to fit. The header gets the new sample rate and recomputed size
+ * and count fields. An audio record with more samples than its data holds
+ * is an error.
+ */
+pub fn replace_audio<W: Write + Seek>(buf: &[u8], track: &AudioTrack, output: W) -> Result<W> {
+    let mut header = HVQM2Header::parse(buf)?;
+    header.check_magic()?;
+
+    let old_rate = header.samples_per_sec;
+    header.samples_per_sec = track.sample_rate;
+
+    let mut encoder = AudioEncoder::new(&header)?;
+    let channels = encoder.channels() as usize;
+    let track = track.with_channels(encoder.channels())?;
+
+    let mut writer = HVQM2Writer::new(output, &header)?;
+    let mut old_position = 0u64;    /* Samples per channel up to the current record, at the old rate */
+    let mut new_position = 0usize;  /* Samples per channel already written, at the new rate */
+
+    for entry in Records::new(buf) {
+        let entry = entry?;
+
+        let Record::Audio { format, header: audio_header, data } = &entry.record else {
+            writer.write_raw_record(&entry.header, entry.data)?;
+            continue;
+        };
+
+        /* A corrupted sample count must not size the new record */
+        let needed = format.to_adpcm_format()?.encoded_size(audio_header.samples);
+        if data.len() < needed {
+            let err = Error::Truncated {
+                structure: "ADPCM data",
+                offset: entry.offset + (HVQM2Record::SIZE + HVQM2AudioHeader::SIZE) as u64,
+                needed,
+                available: data.len(),
+            };
+            return Err(err.in_record(entry.index, entry.offset));
+        }
+
+        old_position += audio_header.samples as u64;
+        let new_end = match old_rate {
+            0 => old_position as usize,
+            _ => ((old_position * track.sample_rate as u64 + old_rate as u64 / 2) / old_rate as u64) as usize,
+        };
+
+        let mut samples = vec![0i16; (new_end - new_position) * channels];
+        let available = track.samples.get(new_position * channels..).unwrap_or(&[]);
+        let copied = samples.len().min(available.len());
+        samples[..copied].copy_from_slice(&available[..copied]);
+        new_position = new_end;
+
+        writer.write_record(*format, &encoder.encode_record(*format, &samples)?)?;
+    }
+
+    writer.finish_with_summary()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::hvqm::DataFormat;
+
+    /* Mono file with one audio record of `samples` samples whose data is `size` bytes */
+    fn file(samples: u32, size: usize) -> Vec<u8> {
+        let mut header = HVQM2Header::for_video(16, 16, 2, 2, 4, 33333, 1);
+        header.samples_per_sec = 8000;
+        let mut writer = HVQM2Writer::new(Cursor::new(Vec::new()), &header).unwrap();
+        let mut data = HVQM2AudioHeader { samples }.to_bytes().to_vec();
+        data.resize(HVQM2AudioHeader::SIZE + size, 0x11);
+        writer.write_record(DataFormat::AudioKeyframe, &data).unwrap();
+        writer.finish_with_summary().unwrap().into_inner()
+    }
+
+    fn track(frames: usize) -> AudioTrack {
+        AudioTrack { channels: 1, sample_rate: 8000, samples: vec![100; frames] }
+    }
+
+    #[test]
+    fn audio_is_replaced_with_the_same_sample_counts() {
+        let file = file(400, 202);
+        let mut padded = file.clone();
+        padded.resize(file.len() + 100, 0);
+
+        for input in [&file, &padded] {
+            let output = replace_audio(input, &track(300), Cursor::new(Vec::new())).unwrap().into_inner();
+            let entries: Vec<_> = Records::new(&output).map(|entry| entry.unwrap()).collect();
+            assert_eq!(entries.len(), 1);
+            match &entries[0].record {
+                Record::Audio { header, data, .. } => {
+                    assert_eq!(header.samples, 400);
+                    assert_eq!(data.len(), 202);
+                },
+                _ => panic!("audio record expected"),
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_sample_count_is_an_error() {
+        let file = file(654_311_957, 263);
+        match replace_audio(&file, &track(300), Cursor::new(Vec::new())) {
+            Err(Error::InRecord { index: 0, source, .. }) => {
+                assert!(matches!(*source, Error::Truncated { structure: "ADPCM data", available: 263, .. }));
+            },
+            _ => panic!("corrupted sample count accepted"),
+        }
+    }
+}
@@ -0,0 +1,749 @@
+use std::io::{Seek, Write};
+
+use crate::audio::AudioEncoder;
+use crate::edit::AudioTrack;
+use crate::error::{Error, Result};
+use crate::hvqm::{DataFormat, HVQM2Frame, HVQM2Header, HVQM2KeyFrame, HVQM2PredictFrame};
+use crate::mux::HVQM2Writer;
+use crate::video::{Nest, Picture, Plane, VideoDecoder, BLOCK_SIZE, MAX_AOT_BASES, MB_INTER, MB_INTRA, MB_SKIP, NEST_SIZE_L, NEST_SIZE_S, ORIGINAL_BLOCK};
+use crate::video::motion_compensate;
+
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 30;
+pub const DEFAULT_BLOCK_ERROR_LIMIT: u32 = 256;    /* Sum of squared errors of a 4x4 block (RMS error of 4) */
+
+const SEARCH_RANGE: isize = 4;    /* Motion vectors are searched up to this many luma pixels away */
+const SECTION_ALIGN: usize = 4;   /* Sections start on word boundaries */
+
+/*
+ * EncodedFrame : Data of one video record (excluding the record header)
+ */
+pub struct EncodedFrame {
+    pub format: DataFormat,    /* VideoKeyframe, VideoPredict or VideoHold */
+    pub data: Vec<u8>,
+}
+
+/*
+ * Writer of a value stream with zero run-length compression, the inverse
+ * of the decoder's RunStream: a zero is stored once in `values` with the
+ * number of zeroes following it in `runs`.
+ */
+#[derive(Default)]
+struct RunWriter {
+    values: Vec<u8>,
+    runs: Vec<u8>,
+    in_run: bool,    /* The last value written is a zero whose run can grow */
+}
+
+impl RunWriter {
+    fn push(&mut self, value: u8) {
+        if value == 0 && self.in_run {
+            let run = self.runs.last_mut().unwrap();
+            if *run < u8::MAX {
+                *run += 1;
+                return;
+            }
+        }
+
+        self.values.push(value);
+        self.in_run = value == 0;
+        if self.in_run {
+            self.runs.push(0);
+        }
+    }
+}
+
+/*
+ * Writer of the 2-bit macroblock state flags, most significant bits first
+ */
+#[derive(Default)]
+struct FlagWriter {
+    bytes: Vec<u8>,
+    count: usize,
+}
+
+impl FlagWriter {
+    fn push(&mut self, flag: u8) {
+        if self.count.is_multiple_of(4) {
+            self.bytes.push(0);
+        }
+
+        *self.bytes.last_mut().unwrap() |= flag << (6 - 2 * (self.count % 4));
+        self.count += 1;
+    }
+}
+
+/*
+ * Per-plane streams of the blocks being coded
+ */
+#[derive(Default)]
+struct BlockWriter {
+    scale: Vec<u8>,
+    fixvl: Vec<u8>,
+}
+
+/*
+ * One AOT basis the encoder can choose, taken from the nest
+ */
+#[derive(Clone)]
+struct Candidate {
+    code: u16,
+    basis: [i32; 16],
+    energy: i64,    /* Sum of the squared basis values */
+}
+
+/*
+ * VideoEncoder : Turns pictures into video records
+ *
+ * Every record is decoded again with a `VideoDecoder` as soon as it is
+ * produced, so predicted frames are coded against exactly the picture the
+ * player will have.
+ *
+ * Blocks are coded with up to 7 AOT bases picked greedily from the nest
+ * until their squared error falls under the block error limit. Blocks that
+ * don't get there are stored as raw pixels.
+ */
+#[derive(Clone)]
+pub struct VideoEncoder {
+    width: usize,
+    height: usize,
+    h_sampling_rate: usize,
+    v_sampling_rate: usize,
+    quantize_shift: u32,
+    keyframe_interval: u32,
+    block_error_limit: u32,
+    frames_since_keyframe: u32,    /* Frames since the last key frame, that one included */
+    candidates: Vec<Candidate>,    /* Bases of the nest of the last key frame */
+    decoder: VideoDecoder,
+    has_keyframe: bool,
+    last_source: Option<Picture>,  /* Last picture coded (not held) */
+}
+
+impl VideoEncoder {
+    /* Uses the size, sampling rates and quantize shift of `header` */
+    pub fn new(header: &HVQM2Header) -> Result<VideoEncoder> {
+        Ok(VideoEncoder {
+            width: header.width as usize,
+            height: header.height as usize,
+            h_sampling_rate: header.h_sampling_rate.max(1) as usize,
+            v_sampling_rate: header.v_sampling_rate.max(1) as usize,
+            quantize_shift: header.video_quantize_shift as u32,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            block_error_limit: DEFAULT_BLOCK_ERROR_LIMIT,
+            frames_since_keyframe: 0,
+            candidates: Vec::new(),
+            decoder: VideoDecoder::new(header)?,
+            has_keyframe: false,
+            last_source: None,
+        })
+    }
+
+    pub fn keyframe_interval(&self) -> u32 {
+        self.keyframe_interval
+    }
+
+    /* A key frame is coded every `interval` frames, 0 for only the first one */
+    pub fn set_keyframe_interval(&mut self, interval: u32) {
+        self.keyframe_interval = interval;
+    }
+
+    pub fn block_error_limit(&self) -> u32 {
+        self.block_error_limit
+    }
+
+    /* Lower limits give better pictures and bigger records */
+    pub fn set_block_error_limit(&mut self, limit: u32) {
+        self.block_error_limit = limit;
+    }
+
+    /* Picture the player shows after the last record produced */
+    pub fn picture(&self) -> &Picture {
+        self.decoder.picture()
+    }
+
+    /* Picture size and layout the encoder expects */
+    pub fn new_picture(&self) -> Picture {
+        Picture::new(self.width, self.height, self.h_sampling_rate, self.v_sampling_rate)
+    }
+
+    /*
+     * Codes the next picture: as a key frame at the keyframe interval, as
+     * a hold if it is identical to the last picture coded, otherwise as a
+     * predicted frame.
+     */
+    pub fn encode(&mut self, picture: &Picture) -> Result<EncodedFrame> {
+        self.check_size(picture)?;
+
+        match self.next_format(picture) {
+            DataFormat::VideoKeyframe => self.encode_keyframe(picture),
+            DataFormat::VideoHold => self.hold(),
+            _ => self.encode_predict(picture),
+        }
+    }
+
+    /* Record format `encode` would use for `picture` */
+    pub fn next_format(&self, picture: &Picture) -> DataFormat {
+        let keyframe_due = self.keyframe_interval > 0 && self.frames_since_keyframe >= self.keyframe_interval;
+        if !self.has_keyframe || keyframe_due {
+            return DataFormat::VideoKeyframe;
+        }
+
+        let unchanged = self.last_source.as_ref().is_some_and(|last| {
+            last.y.pixels == picture.y.pixels && last.u.pixels == picture.u.pixels && last.v.pixels == picture.v.pixels
+        });
+        if unchanged {
+            DataFormat::VideoHold
+        } else {
+            DataFormat::VideoPredict
+        }
+    }
+
+    /* Shows the last picture again for one more frame, fails before the first key frame */
+    pub fn hold(&mut self) -> Result<EncodedFrame> {
+        self.decoder.hold()?;
+        self.frames_since_keyframe += 1;
+
+        Ok(EncodedFrame {
+            format: DataFormat::VideoHold,
+            data: Vec::new(),
+        })
+    }
+
+    pub fn encode_keyframe(&mut self, picture: &Picture) -> Result<EncodedFrame> {
+        self.check_size(picture)?;
+
+        let mut dcval: [RunWriter; 3] = Default::default();
+        let dc_planes: Vec<Plane> = (0..3).map(|plane| encode_dc_plane(picture.plane(plane), &mut dcval[plane])).collect();
+
+        let mut nest = Nest::new();
+        nest.build(&dc_planes[0], 0, 0);
+        self.candidates = nest_candidates(&nest, NEST_SIZE_L.min(dc_planes[0].width), NEST_SIZE_S.min(dc_planes[0].height))?;
+
+        let mut basisnum: [RunWriter; 2] = Default::default();
+        let mut streams: [BlockWriter; 3] = Default::default();
+
+        for (plane, dc) in dc_planes.iter().enumerate() {
+            let source = picture.plane(plane);
+            for by in 0..dc.height {
+                for bx in 0..dc.width {
+                    let target = load_block(source, bx, by);
+                    let basis_count = self.encode_block(&target, [dc.get(bx, by) as i32; 16], &mut streams[plane]);
+                    basisnum[plane.min(1)].push(basis_count);
+                }
+            }
+        }
+
+        let [dcval_y, dcval_u, dcval_v] = dcval;
+        let mut sections = common_sections(&basisnum, &streams, [&dcval_y.values, &dcval_u.values, &dcval_v.values]);
+        sections.extend([&dcval_y.runs[..], &dcval_u.runs, &dcval_v.runs]);
+
+        let (frame, offsets, body) = layout(&sections, HVQM2Frame::SIZE + HVQM2KeyFrame::SIZE);
+        let key_frame = HVQM2KeyFrame {
+            dcrun_offset: [offsets[13], offsets[14], offsets[15]],
+            nest_start_x: 0,
+            nest_start_y: 0,
+        };
+
+        let mut data = frame.to_bytes().to_vec();
+        data.extend(key_frame.to_bytes());
+        data.extend(body);
+
+        self.decoder.decode_keyframe(&data)?;
+        self.has_keyframe = true;
+        self.frames_since_keyframe = 1;
+        self.last_source = Some(picture.clone());
+
+        Ok(EncodedFrame {
+            format: DataFormat::VideoKeyframe,
+            data,
+        })
+    }
+
+    /*
+     * Codes `picture` against the last picture produced. Every macroblock
+     * is skipped if it barely changed, or else motion compensated (INTER)
+     * or coded on its own (INTRA), whichever predicts it better.
+     */
+    pub fn encode_predict(&mut self, picture: &Picture) -> Result<EncodedFrame> {
+        self.check_size(picture)?;
+        if !self.has_keyframe {
+            return Err(Error::MissingKeyframe);
+        }
+
+        let reference = self.decoder.picture();
+        let mut flags = FlagWriter::default();
+        let mut movevector = Vec::new();
+        let mut dcval: [Vec<u8>; 3] = Default::default();
+        let mut basisnum: [RunWriter; 2] = Default::default();
+        let mut streams: [BlockWriter; 3] = Default::default();
+
+        let mcus_wide = reference.u.blocks_wide();
+        let mcus_high = reference.u.blocks_high();
+
+        for mcu_y in 0..mcus_high {
+            for mcu_x in 0..mcus_wide {
+                let blocks = self.mcu_blocks(mcu_x, mcu_y);
+
+                let skip_error: i64 = blocks.iter()
+                    .map(|&(plane, bx, by)| squared_error(&load_block(picture.plane(plane), bx, by), &motion_compensate(reference.plane(plane), bx, by, 0, 0)))
+                    .sum();
+                if skip_error <= self.block_error_limit as i64 * blocks.len() as i64 {
+                    flags.push(MB_SKIP);
+                    continue;
+                }
+
+                let (dx, dy) = self.motion_search(picture, reference, mcu_x, mcu_y);
+                let mut inter = Vec::with_capacity(blocks.len());
+                let mut inter_cost = 0;
+                let mut intra_cost = 0;
+
+                for &(plane, bx, by) in &blocks {
+                    let (mv_x, mv_y) = self.plane_vector(plane, dx, dy);
+                    let target = load_block(picture.plane(plane), bx, by);
+                    let prediction = motion_compensate(reference.plane(plane), bx, by, mv_x, mv_y);
+
+                    let difference: i32 = target.iter().zip(prediction).map(|(&t, p)| t - p).sum();
+                    let dc = div_round(difference as i64, 16).clamp(i8::MIN as i64, i8::MAX as i64) as i32;
+                    let prediction = prediction.map(|value| value + dc);
+
+                    inter_cost += absolute_error(&target, &prediction);
+                    intra_cost += absolute_error(&target, &[block_mean(&target); 16]);
+                    inter.push((dc, prediction));
+                }
+
+                let state = if inter_cost <= intra_cost { MB_INTER } else { MB_INTRA };
+                flags.push(state);
+                if state == MB_INTER {
+                    movevector.extend([dx as i8 as u8, dy as i8 as u8]);
+                }
+
+                for (&(plane, bx, by), (dc, prediction)) in blocks.iter().zip(inter) {
+                    let target = load_block(picture.plane(plane), bx, by);
+                    let basis_count = if state == MB_INTER {
+                        dcval[plane].push(dc as i8 as u8);
+                        self.encode_block(&target, prediction, &mut streams[plane])
+                    } else {
+                        let mean = block_mean(&target);
+                        dcval[plane].push(mean as u8);
+                        self.encode_block(&target, [mean; 16], &mut streams[plane])
+                    };
+                    basisnum[plane.min(1)].push(basis_count);
+                }
+            }
+        }
+
+        let mut sections = common_sections(&basisnum, &streams, [&dcval[0], &dcval[1], &dcval[2]]);
+        sections.extend([&movevector[..], &flags.bytes]);
+
+        let (frame, offsets, body) = layout(&sections, HVQM2Frame::SIZE + HVQM2PredictFrame::SIZE);
+        let predict_frame = HVQM2PredictFrame {
+            movevector_offset: offsets[13],
+            macroblock_offset: offsets[14],
+        };
+
+        let mut data = frame.to_bytes().to_vec();
+        data.extend(predict_frame.to_bytes());
+        data.extend(body);
+
+        self.decoder.decode_predict(&data)?;
+        self.frames_since_keyframe += 1;
+        self.last_source = Some(picture.clone());
+
+        Ok(EncodedFrame {
+            format: DataFormat::VideoPredict,
+            data,
+        })
+    }
+
+    fn check_size(&self, picture: &Picture) -> Result<()> {
+        let expected = self.new_picture();
+        let same_planes = (0..3).all(|plane| {
+            let (a, b) = (picture.plane(plane), expected.plane(plane));
+            a.width == b.width && a.height == b.height && a.pixels.len() == b.pixels.len()
+        });
+
+        if picture.width != self.width || picture.height != self.height || !same_planes {
+            return Err(Error::FrameSizeMismatch { expected: (self.width, self.height), found: (picture.width, picture.height) });
+        }
+        Ok(())
+    }
+
+    /* Blocks of a macroblock in decoding order: (plane, block x, block y) */
+    fn mcu_blocks(&self, mcu_x: usize, mcu_y: usize) -> Vec<(usize, usize, usize)> {
+        let mut blocks = Vec::with_capacity(self.h_sampling_rate * self.v_sampling_rate + 2);
+        for j in 0..self.v_sampling_rate {
+            for i in 0..self.h_sampling_rate {
+                blocks.push((0, mcu_x * self.h_sampling_rate + i, mcu_y * self.v_sampling_rate + j));
+            }
+        }
+        blocks.push((1, mcu_x, mcu_y));
+        blocks.push((2, mcu_x, mcu_y));
+        blocks
+    }
+
+    /* Motion vector of `plane` for the luma vector (`dx`, `dy`) */
+    fn plane_vector(&self, plane: usize, dx: isize, dy: isize) -> (isize, isize) {
+        match plane {
+            0 => (dx, dy),
+            _ => (dx.div_euclid(self.h_sampling_rate as isize), dy.div_euclid(self.v_sampling_rate as isize)),
+        }
+    }
+
+    /* Luma motion vector of a macroblock with the smallest absolute error */
+    fn motion_search(&self, picture: &Picture, reference: &Picture, mcu_x: usize, mcu_y: usize) -> (isize, isize) {
+        let blocks: Vec<_> = (0..self.v_sampling_rate)
+            .flat_map(|j| (0..self.h_sampling_rate).map(move |i| (i, j)))
+            .map(|(i, j)| (mcu_x * self.h_sampling_rate + i, mcu_y * self.v_sampling_rate + j))
+            .map(|(bx, by)| (bx, by, load_block(&picture.y, bx, by)))
+            .collect();
+
+        let mut best: ((isize, isize), i64) = ((0, 0), i64::MAX);
+        for dy in -SEARCH_RANGE..=SEARCH_RANGE {
+            for dx in -SEARCH_RANGE..=SEARCH_RANGE {
+                let cost: i64 = blocks.iter()
+                    .map(|(bx, by, target)| absolute_error(target, &motion_compensate(&reference.y, *bx, *by, dx, dy)))
+                    .sum();
+
+                /* Prefer the shorter vector on ties */
+                if cost < best.1 || (cost == best.1 && dx.abs() + dy.abs() < best.0 .0.abs() + best.0 .1.abs()) {
+                    best = ((dx, dy), cost);
+                }
+            }
+        }
+        best.0
+    }
+
+    /*
+     * Codes one block as `prediction` plus AOT bases (or as raw pixels) and
+     * returns its basis number
+     */
+    fn encode_block(&self, target: &[i32; 16], prediction: [i32; 16], streams: &mut BlockWriter) -> u8 {
+        let limit = self.block_error_limit as i64;
+        let shift = self.quantize_shift + 4;
+
+        let mut sum = [0i32; 16];
+        let mut reconstructed = reconstruct(&prediction, &sum, shift);
+        let mut error = squared_error(target, &reconstructed);
+        let mut chosen: Vec<(u16, i8)> = Vec::new();
+
+        while error > limit && chosen.len() < MAX_AOT_BASES as usize {
+            let residual: Vec<i64> = target.iter().zip(reconstructed).map(|(&t, r)| (t - r) as i64).collect();
+            let correlation = |candidate: &Candidate| -> i64 { residual.iter().zip(candidate.basis).map(|(&r, b)| r * b as i64).sum() };
+
+            let Some((candidate, dot)) = self.candidates.iter()
+                .map(|candidate| (candidate, correlation(candidate)))
+                .max_by_key(|(candidate, dot)| dot * dot / candidate.energy) else {
+                break;
+            };
+
+            let scale = div_round(dot << shift, candidate.energy).clamp(i8::MIN as i64, i8::MAX as i64) as i32;
+            if scale == 0 {
+                break;
+            }
+
+            let mut next_sum = sum;
+            for (acc, value) in next_sum.iter_mut().zip(candidate.basis) {
+                *acc += scale * value;
+            }
+            let next = reconstruct(&prediction, &next_sum, shift);
+            let next_error = squared_error(target, &next);
+            if next_error >= error {
+                break;
+            }
+
+            sum = next_sum;
+            reconstructed = next;
+            error = next_error;
+            chosen.push((candidate.code, scale as i8));
+        }
+
+        if error > limit {
+            streams.fixvl.extend(target.map(|value| value as u8));
+            return ORIGINAL_BLOCK;
+        }
+
+        for &(code, scale) in &chosen {
+            streams.fixvl.extend(code.to_be_bytes());
+            streams.scale.push(scale as u8);
+        }
+        chosen.len() as u8
+    }
+}
+
+/*
+ * MovieWriter : Writes a new file from a sequence of pictures and an
+ * optional audio track
+ *
+ * Every video record is preceded by an audio record holding the samples up
+ * to the end of its frame interval. The audio is always written as mono,
+ * stereo tracks are mixed down. The audio record restarts the ADPCM
+ * state (AudioKeyframe) when the video record is a key frame, so playback
+ * can start at any key frame.
+ */
+pub struct MovieWriter<W: Write> {
+    writer: HVQM2Writer<W>,
+    video: VideoEncoder,
+    audio: Option<(AudioEncoder, AudioTrack)>,
+    usec_per_frame: u64,
+    frames: u64,             /* Video records written */
+    audio_position: usize,   /* Samples per channel written */
+}
+
+impl<W: Write> MovieWriter<W> {
+    /*
+     * Writes `header` with the audio fields of `audio` (if any) and starts
+     * encoding with its video parameters
+     */
+    pub fn new(output: W, header: &HVQM2Header, audio: Option<&AudioTrack>) -> Result<MovieWriter<W>> {
+        let mut header = header.clone();
+        let audio = match audio {
+            Some(track) => {
+                header.channels = 1;
+                header.samples_per_sec = track.sample_rate;
+                Some((AudioEncoder::new(&header)?, track.with_channels(1)?))
+            },
+            None => None,
+        };
+
+        Ok(MovieWriter {
+            writer: HVQM2Writer::new(output, &header)?,
+            video: VideoEncoder::new(&header)?,
+            audio,
+            usec_per_frame: header.usec_per_frame as u64,
+            frames: 0,
+            audio_position: 0,
+        })
+    }
+
+    pub fn video(&self) -> &VideoEncoder {
+        &self.video
+    }
+
+    pub fn video_mut(&mut self) -> &mut VideoEncoder {
+        &mut self.video
+    }
+
+    /* Encodes and writes the next picture, returns the video record format used */
+    pub fn write_picture(&mut self, picture: &Picture) -> Result<DataFormat> {
+        let frame = self.video.encode(picture)?;
+        self.write_frame(&frame)?;
+        Ok(frame.format)
+    }
+
+    /*
+     * Writes a frame produced by `video_mut()`, after the audio of its
+     * frame interval
+     */
+    pub fn write_frame(&mut self, frame: &EncodedFrame) -> Result<()> {
+        self.frames += 1;
+
+        if let Some((encoder, track)) = self.audio.as_mut() {
+            let channels = encoder.channels() as usize;
+            let end = ((self.frames * self.usec_per_frame * track.sample_rate as u64 + 500_000) / 1_000_000) as usize;
+
+            let mut samples = vec![0i16; (end - self.audio_position) * channels];
+            let available = track.samples.get(self.audio_position * channels..).unwrap_or(&[]);
+            let copied = samples.len().min(available.len());
+            samples[..copied].copy_from_slice(&available[..copied]);
+            self.audio_position = end;
+
+            let format = match frame.format {
+                DataFormat::VideoKeyframe => DataFormat::AudioKeyframe,
+                _ => DataFormat::AudioPredict,
+            };
+            self.writer.write_record(format, &encoder.encode_record(format, &samples)?)?;
+        }
+
+        self.writer.write_record(frame.format, &frame.data)
+    }
+
+    /* Header with the size and count fields of the records written so far */
+    pub fn summary_header(&self) -> &HVQM2Header {
+        self.writer.summary_header()
+    }
+}
+
+impl<W: Write + Seek> MovieWriter<W> {
+    /* Rewrites the header with the final size and count fields */
+    pub fn finish(self) -> Result<W> {
+        self.writer.finish_with_summary()
+    }
+}
+
+/*
+ * Computes the DC (mean) of every block of `plane` and writes each as a
+ * delta from the same predictor the decoder uses
+ */
+fn encode_dc_plane(plane: &Plane, dcval: &mut RunWriter) -> Plane {
+    let mut dc = Plane::new(plane.blocks_wide(), plane.blocks_high());
+
+    for by in 0..dc.height {
+        for bx in 0..dc.width {
+            let predictor = if bx > 0 {
+                dc.get(bx - 1, by)
+            } else if by > 0 {
+                dc.get(bx, by - 1)
+            } else {
+                0x80
+            };
+
+            let value = block_mean(&load_block(plane, bx, by)) as u8;
+            dc.set(bx, by, value);
+            dcval.push(value.wrapping_sub(predictor));
+        }
+    }
+
+    dc
+}
+
+/*
+ * Every basis of the nest at even positions, with all four steps.
+ * Flat bases (all zero after removing the mean) are left out.
+ */
+fn nest_candidates(nest: &Nest, width: usize, height: usize) -> Result<Vec<Candidate>> {
+    let mut candidates = Vec::new();
+
+    for y in (0..height).step_by(2) {
+        for x in (0..width).step_by(2) {
+            for step in 0..4u16 {
+                let code = x as u16 | (y as u16) << 7 | step << 13;
+                let basis = nest.basis(code)?;
+                let energy = basis.iter().map(|&value| value as i64 * value as i64).sum();
+
+                if energy > 0 {
+                    candidates.push(Candidate { code, basis, energy });
+                }
+            }
+        }
+    }
+
+    Ok(candidates)
+}
+
+/* Sections shared by both frame types, in HVQM2Frame order */
+fn common_sections<'a>(basisnum: &'a [RunWriter; 2], streams: &'a [BlockWriter; 3], dcval: [&'a [u8]; 3]) -> Vec<&'a [u8]> {
+    vec![
+        &basisnum[0].values, &basisnum[1].values,
+        &basisnum[0].runs, &basisnum[1].runs,
+        &streams[0].scale, &streams[1].scale, &streams[2].scale,
+        &streams[0].fixvl, &streams[1].fixvl, &streams[2].fixvl,
+        dcval[0], dcval[1], dcval[2],
+    ]
+}
+
+/*
+ * Places `sections` one after the other after `headers_size` bytes of
+ * headers. Returns the frame header, the offset of every section and the
+ * bytes following the headers.
+ */
+fn layout(sections: &[&[u8]], headers_size: usize) -> (HVQM2Frame, Vec<u32>, Vec<u8>) {
+    let mut offsets = Vec::with_capacity(sections.len());
+    let mut body = Vec::new();
+
+    for section in sections {
+        body.resize((headers_size + body.len()).next_multiple_of(SECTION_ALIGN) - headers_size, 0);
+        offsets.push((headers_size + body.len()) as u32);
+        body.extend_from_slice(section);
+    }
+
+    let frame = HVQM2Frame {
+        basisnum_offset: [offsets[0], offsets[1]],
+        basnumrn_offset: [offsets[2], offsets[3]],
+        scale_offset: [offsets[4], offsets[5], offsets[6]],
+        fixvl_offset: [offsets[7], offsets[8], offsets[9]],
+        dcval_offset: [offsets[10], offsets[11], offsets[12]],
+    };
+    (frame, offsets, body)
+}
+
+/* Mirrors the decoder: `prediction` plus the scaled bases, clamped */
+fn reconstruct(prediction: &[i32; 16], sum: &[i32; 16], shift: u32) -> [i32; 16] {
+    let mut block = [0; 16];
+    for ((pixel, base), acc) in block.iter_mut().zip(prediction).zip(sum) {
+        *pixel = (base + (acc >> shift)).clamp(0, 255);
+    }
+    block
+}
+
+fn load_block(plane: &Plane, bx: usize, by: usize) -> [i32; 16] {
+    let mut block = [0; 16];
+    for j in 0..BLOCK_SIZE {
+        for i in 0..BLOCK_SIZE {
+            block[j * BLOCK_SIZE + i] = plane.get(bx * BLOCK_SIZE + i, by * BLOCK_SIZE + j) as i32;
+        }
+    }
+    block
+}
+
+fn block_mean(block: &[i32; 16]) -> i32 {
+    (block.iter().sum::<i32>() + 8) / 16
+}
+
+fn squared_error(a: &[i32; 16], b: &[i32; 16]) -> i64 {
+    a.iter().zip(b).map(|(&a, &b)| ((a - b) * (a - b)) as i64).sum()
+}
+
+fn absolute_error(a: &[i32; 16], b: &[i32; 16]) -> i64 {
+    a.iter().zip(b).map(|(&a, &b)| (a - b).abs() as i64).sum()
+}
+
+/* Division rounded to the nearest integer, halves away from zero */
+fn div_round(numerator: i64, denominator: i64) -> i64 {
+    if numerator >= 0 {
+        (numerator + denominator / 2) / denominator
+    } else {
+        (numerator - denominator / 2) / denominator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hvqm::{HVQM2Record, Record};
+
+    /* Smooth gradients that drift with `frame`, with the chroma at rest on odd frames */
+    fn synthetic_picture(encoder: &VideoEncoder, frame: usize) -> Picture {
+        let mut picture = encoder.new_picture();
+        for (index, plane) in [&mut picture.y, &mut picture.u, &mut picture.v].into_iter().enumerate() {
+            for y in 0..plane.height {
+                for x in 0..plane.width {
+                    let drift = if index == 0 { frame * 3 } else { frame / 2 * 5 };
+                    plane.set(x, y, (40 + x * 4 + y * 2 + drift + index * 30).min(255) as u8);
+                }
+            }
+        }
+        picture
+    }
+
+    #[test]
+    fn decoder_shows_the_encoder_picture() {
+        let header = HVQM2Header::for_video(40, 24, 2, 1, 4, 33333, 1);
+        let mut encoder = VideoEncoder::new(&header).unwrap();
+        encoder.set_keyframe_interval(4);
+        let mut decoder = VideoDecoder::new(&header).unwrap();
+
+        let mut formats = Vec::new();
+        for frame in 0..8 {
+            let picture = synthetic_picture(&encoder, frame.min(6));
+            let encoded = encoder.encode(&picture).unwrap();
+            formats.push(encoded.format);
+
+            let record_header = HVQM2Record::for_data(encoded.format, encoded.data.len() as u32);
+            let record = Record::parse(&record_header, &encoded.data).unwrap();
+            let decoded = decoder.decode(encoded.format, record.video_payload()).unwrap();
+
+            let expected = encoder.picture();
+            assert_eq!(decoded.y.pixels, expected.y.pixels, "frame {frame}");
+            assert_eq!(decoded.u.pixels, expected.u.pixels, "frame {frame}");
+            assert_eq!(decoded.v.pixels, expected.v.pixels, "frame {frame}");
+
+            /* No pixel of a block within the default error limit is off by more than 16 */
+            let error = decoded.y.pixels.iter().zip(&picture.y.pixels).map(|(&a, &b)| a.abs_diff(b) as u32).max().unwrap();
+            assert!(error <= 16, "frame {frame}: error {error}");
+        }
+
+        assert_eq!(formats[0], DataFormat::VideoKeyframe);
+        assert_eq!(formats[4], DataFormat::VideoKeyframe);
+        assert!(formats.contains(&DataFormat::VideoPredict));
+        assert_eq!(formats[7], DataFormat::VideoHold);
+    }
+}
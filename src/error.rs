@@ -0,0 +1,142 @@
+use std::fmt;
+
+use crate::hvqm::{AudioFormat, DataFormat, RecordType};
+
+/*
+ * Error : Everything that can go wrong while reading or decoding HVQM2 data
+ */
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    #[cfg(feature = "experimental-video")]
+    Png(png::EncodingError),
+    #[cfg(feature = "experimental-video")]
+    PngDecode(png::DecodingError),
+    Wav(hound::Error),
+
+    /* The file does not start with "HVQM2 1.0" */
+    BadMagic { found: [u8; 16] },
+    /* `structure` needs `needed` bytes at `offset` but only `available` are left */
+    Truncated { structure: &'static str, offset: u64, needed: usize, available: usize },
+    UnknownRecordType { record_type: u16 },
+    UnknownFormat { record_type: RecordType, format: u16 },
+    /* A record header says `declared` bytes of data but `actual` were given */
+    RecordSizeMismatch { declared: u32, actual: usize },
+    /* A record of `found` format was given where a `expected` record was needed */
+    UnexpectedFormat { expected: &'static str, found: DataFormat },
+    /* Section `section` starts at `offset`, inside the record headers or past the end of its record (`limit` bytes) */
+    OffsetOutOfRange { section: &'static str, offset: u64, limit: u64 },
+
+    InvalidBasisNumber { value: u8 },
+    /* An ADPCM Reset header with a step index past the end of the step table */
+    InvalidStepIndex { value: u8 },
+    UnknownAudioFormat { format: u8 },
+    /* Audio with `channels` channels, only mono can be decoded */
+    UnsupportedChannels { channels: u8 },
+    /* `format` audio can't be stored with `sample_bits` bits per sample */
+    UnsupportedSampleBits { format: AudioFormat, sample_bits: u8 },
+    InvalidMacroblockState { value: u8 },
+    /* A header `video_quantize_shift` over `max`, the largest one the decoder handles */
+    UnsupportedQuantizeShift { value: u8, max: u8 },
+    /* A header `y_shiftnum` other than 0, whose effect on the nest is unknown */
+    UnsupportedYShift { value: u8 },
+    /* A frame that depends on a previous picture came before any key frame */
+    MissingKeyframe,
+
+    /* A YUV4MPEG2 stream that can't be read */
+    InvalidY4m { reason: &'static str },
+    /* A picture to encode is not the size given in the file header */
+    FrameSizeMismatch { expected: (usize, usize), found: (usize, usize) },
+    /* The first key frame takes `size` bytes at the lowest quality, over the `limit` byte frame size limit */
+    FrameTooLarge { size: usize, limit: u32 },
+
+    /* Wraps an error with the record it happened in */
+    InRecord { index: u32, offset: u64, source: Box<Error> },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /* Attaches the index and file offset of the record being processed */
+    pub fn in_record(self, index: u32, offset: u64) -> Error {
+        Error::InRecord {
+            index,
+            offset,
+            source: Box::new(self),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {err}"),
+            #[cfg(feature = "experimental-video")]
+            Error::Png(err) => write!(f, "PNG error: {err}"),
+            #[cfg(feature = "experimental-video")]
+            Error::PngDecode(err) => write!(f, "PNG error: {err}"),
+            Error::Wav(err) => write!(f, "WAV error: {err}"),
+            Error::BadMagic { found } => write!(f, "bad file version {:?}, expected \"HVQM2 1.0\"", String::from_utf8_lossy(found).trim_end_matches('\0')),
+            Error::Truncated { structure, offset, needed, available } => write!(f, "truncated {structure} at offset 0x{offset:X}: needs {needed} bytes, only {available} available"),
+            Error::UnknownRecordType { record_type } => write!(f, "unknown record type {record_type}"),
+            Error::UnknownFormat { record_type, format } => write!(f, "unknown {record_type:?} data format {format}"),
+            Error::RecordSizeMismatch { declared, actual } => write!(f, "record header declares {declared} bytes of data but {actual} were given"),
+            Error::UnexpectedFormat { expected, found } => write!(f, "expected {expected} record, found {found:?}"),
+            Error::OffsetOutOfRange { section, offset, limit } => write!(f, "{section} offset 0x{offset:X} is outside of the record (0x{limit:X} bytes)"),
+            Error::InvalidBasisNumber { value } => write!(f, "invalid basis number {value}"),
+            Error::InvalidStepIndex { value } => write!(f, "invalid ADPCM step index {value}"),
+            Error::UnknownAudioFormat { format } => write!(f, "unknown audio format {format}"),
+            Error::UnsupportedChannels { channels } => write!(f, "unsupported number of audio channels {channels}"),
+            Error::UnsupportedSampleBits { format, sample_bits } => write!(f, "unsupported {} sample size of {sample_bits} bits", format.description()),
+            Error::InvalidMacroblockState { value } => write!(f, "invalid macroblock state {value}"),
+            Error::UnsupportedQuantizeShift { value, max } => write!(f, "unsupported video quantize shift {value}, at most {max} can be decoded"),
+            Error::UnsupportedYShift { value } => write!(f, "unsupported y_shiftnum {value}, only 0 can be decoded"),
+            Error::MissingKeyframe => write!(f, "no key frame before this frame"),
+            Error::InvalidY4m { reason } => write!(f, "invalid YUV4MPEG2 stream: {reason}"),
+            Error::FrameTooLarge { size, limit } => write!(f, "key frame needs {size} bytes at the lowest quality, over the {limit} byte limit"),
+            Error::FrameSizeMismatch { expected, found } => write!(f, "picture is {}x{}, expected {}x{}", found.0, found.1, expected.0, expected.1),
+            Error::InRecord { index, offset, source } => write!(f, "record {index} at offset 0x{offset:X}: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            #[cfg(feature = "experimental-video")]
+            Error::Png(err) => Some(err),
+            #[cfg(feature = "experimental-video")]
+            Error::PngDecode(err) => Some(err),
+            Error::Wav(err) => Some(err),
+            Error::InRecord { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+#[cfg(feature = "experimental-video")]
+impl From<png::EncodingError> for Error {
+    fn from(err: png::EncodingError) -> Error {
+        Error::Png(err)
+    }
+}
+
+#[cfg(feature = "experimental-video")]
+impl From<png::DecodingError> for Error {
+    fn from(err: png::DecodingError) -> Error {
+        Error::PngDecode(err)
+    }
+}
+
+impl From<hound::Error> for Error {
+    fn from(err: hound::Error) -> Error {
+        Error::Wav(err)
+    }
+}
@@ -0,0 +1,284 @@
+use std::io::{Cursor, Write};
+#[cfg(feature = "experimental-video")]
+use std::{fs::File, io::BufWriter, path::{Path, PathBuf}};
+
+use crate::error::Result;
+#[cfg(feature = "experimental-video")]
+use crate::{color::{self, PixelFormat}, video::Picture};
+
+/*
+ * Writes the displayed area of a picture as an 8-bit RGB PNG, with the
+ * colors it has in a framebuffer of `format`
+ */
+#[cfg(feature = "experimental-video")]
+pub fn write_png(path: &Path, picture: &Picture, format: PixelFormat) -> Result<()> {
+    let file = File::create(path)?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), picture.width as u32, picture.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&color::picture_to_rgb8(picture, format))?;
+    writer.finish()?;
+    Ok(())
+}
+
+/*
+ * PngSequence : Numbered image sequence (frame_00000.png, frame_00001.png, ...)
+ */
+#[cfg(feature = "experimental-video")]
+pub struct PngSequence {
+    directory: PathBuf,
+    format: PixelFormat,
+    next_index: u32,
+}
+
+#[cfg(feature = "experimental-video")]
+impl PngSequence {
+    pub fn new(directory: &Path, format: PixelFormat) -> Result<PngSequence> {
+        std::fs::create_dir_all(directory)?;
+
+        Ok(PngSequence {
+            directory: directory.to_path_buf(),
+            format,
+            next_index: 0,
+        })
+    }
+
+    /* Writes the next displayed frame. Held frames must be written again. */
+    pub fn write_frame(&mut self, picture: &Picture) -> Result<()> {
+        let path = self.directory.join(format!("frame_{:05}.png", self.next_index));
+        write_png(&path, picture, self.format)?;
+
+        self.next_index += 1;
+        Ok(())
+    }
+}
+
+/*
+ * FramebufferDump : Raw framebuffer capture
+ *
+ * Every displayed frame is appended as width*height pixels of the
+ * decoder's pixel format, in the byte order of the N64 framebuffer in RDRAM.
+ * The pixel values come from `color`, which is not the library's own color
+ * conversion, so they are not a substitute for a capture from hardware.
+ */
+pub struct FramebufferDump<W: Write> {
+    output: W,
+}
+
+impl<W: Write> FramebufferDump<W> {
+    pub fn new(output: W) -> FramebufferDump<W> {
+        FramebufferDump {
+            output,
+        }
+    }
+
+    pub fn write_frame(&mut self, framebuffer: &[u8]) -> Result<()> {
+        self.output.write_all(framebuffer)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AudioFileFormat {
+    Wav,         /* RIFF WAVE, little-endian 16-bit PCM */
+    Aiff,        /* AIFF, big-endian 16-bit PCM */
+    RawS16Be,    /* Headerless big-endian 16-bit samples, as on the N64 */
+    RawS16Le,    /* Headerless little-endian 16-bit samples */
+}
+
+impl AudioFileFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFileFormat::Wav => "wav",
+            AudioFileFormat::Aiff => "aiff",
+            AudioFileFormat::RawS16Be => "s16be",
+            AudioFileFormat::RawS16Le => "s16le",
+        }
+    }
+}
+
+/*
+ * AudioWriter : 16-bit interleaved samples to an audio file
+ *
+ * Raw formats are written as the samples arrive. WAV and AIFF need the
+ * total length in their header, so their samples are kept until `finish`.
+ */
+pub struct AudioWriter<W: Write> {
+    output: W,
+    format: AudioFileFormat,
+    channels: u16,
+    sample_rate: u32,
+    pending: Vec<i16>,    /* Samples of WAV and AIFF files */
+}
+
+impl<W: Write> AudioWriter<W> {
+    pub fn new(output: W, format: AudioFileFormat, channels: u16, sample_rate: u32) -> AudioWriter<W> {
+        AudioWriter {
+            output,
+            format,
+            channels,
+            sample_rate,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        match self.format {
+            AudioFileFormat::RawS16Be => write_samples_be(&mut self.output, samples)?,
+            AudioFileFormat::RawS16Le => write_samples_le(&mut self.output, samples)?,
+            AudioFileFormat::Wav | AudioFileFormat::Aiff => self.pending.extend_from_slice(samples),
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        match self.format {
+            AudioFileFormat::Wav => self.write_wav()?,
+            AudioFileFormat::Aiff => self.write_aiff()?,
+            AudioFileFormat::RawS16Be | AudioFileFormat::RawS16Le => (),
+        }
+
+        self.output.flush()?;
+        Ok(self.output)
+    }
+
+    /* hound needs to seek back to its header, so the file is built in memory first */
+    fn write_wav(&mut self) -> Result<()> {
+        let spec = hound::WavSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+
+        let mut file = Cursor::new(Vec::new());
+        let mut wav = hound::WavWriter::new(&mut file, spec)?;
+        for &sample in &self.pending {
+            wav.write_sample(sample)?;
+        }
+        wav.finalize()?;
+
+        self.output.write_all(file.get_ref())?;
+        Ok(())
+    }
+
+    fn write_aiff(&mut self) -> Result<()> {
+        let data_size = self.pending.len() as u32 * 2;
+        let sample_frames = self.pending.len() as u32 / self.channels.max(1) as u32;
+
+        let out = &mut self.output;
+        out.write_all(b"FORM")?;
+        out.write_all(&(4 + 26 + 16 + data_size).to_be_bytes())?;
+        out.write_all(b"AIFF")?;
+
+        out.write_all(b"COMM")?;
+        out.write_all(&18u32.to_be_bytes())?;
+        out.write_all(&self.channels.to_be_bytes())?;
+        out.write_all(&sample_frames.to_be_bytes())?;
+        out.write_all(&16u16.to_be_bytes())?;
+        out.write_all(&extended_from_u32(self.sample_rate))?;
+
+        out.write_all(b"SSND")?;
+        out.write_all(&(8 + data_size).to_be_bytes())?;
+        out.write_all(&0u32.to_be_bytes())?;    /* Offset */
+        out.write_all(&0u32.to_be_bytes())?;    /* Block size */
+        write_samples_be(out, &self.pending)?;
+        Ok(())
+    }
+}
+
+fn write_samples_be(output: &mut impl Write, samples: &[i16]) -> Result<()> {
+    let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_be_bytes()).collect();
+    output.write_all(&bytes)?;
+    Ok(())
+}
+
+fn write_samples_le(output: &mut impl Write, samples: &[i16]) -> Result<()> {
+    let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    output.write_all(&bytes)?;
+    Ok(())
+}
+
+/*
+ * 80-bit IEEE 754 extended precision number, as used by the AIFF sample rate
+ */
+fn extended_from_u32(value: u32) -> [u8; 10] {
+    let mut bytes = [0; 10];
+    if value == 0 {
+        return bytes;
+    }
+
+    let highest_bit = 31 - value.leading_zeros();
+    let exponent = 16383 + highest_bit as u16;
+    let mantissa = (value as u64) << (63 - highest_bit);
+
+    bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+    bytes[2..].copy_from_slice(&mantissa.to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edit::AudioTrack;
+
+    #[test]
+    fn wav_reads_back() {
+        let samples = [0, 1, -1, i16::MAX, i16::MIN, 1234];
+        let mut writer = AudioWriter::new(Vec::new(), AudioFileFormat::Wav, 2, 22050);
+        writer.write_samples(&samples).unwrap();
+        let file = writer.finish().unwrap();
+
+        let track = AudioTrack::read_wav(file.as_slice()).unwrap();
+        assert_eq!((track.channels, track.sample_rate), (2, 22050));
+        assert_eq!(track.samples, samples);
+    }
+
+    #[test]
+    fn aiff_chunks_have_the_right_sizes() {
+        let samples = [0, 1, -1, i16::MAX, i16::MIN, 0x1234];
+        let mut writer = AudioWriter::new(Vec::new(), AudioFileFormat::Aiff, 2, 44100);
+        writer.write_samples(&samples[..2]).unwrap();
+        writer.write_samples(&samples[2..]).unwrap();
+        let file = writer.finish().unwrap();
+
+        let be = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().unwrap());
+        assert_eq!(file.len(), 12 + 26 + 16 + 12);
+        assert_eq!(&file[0..4], b"FORM");
+        assert_eq!(be(&file[4..8]) as usize, file.len() - 8);
+        assert_eq!(&file[8..12], b"AIFF");
+
+        assert_eq!(&file[12..16], b"COMM");
+        assert_eq!(be(&file[16..20]), 18);
+        assert_eq!(file[20..22], 2u16.to_be_bytes());
+        assert_eq!(be(&file[22..26]), 3);    /* Sample frames */
+        assert_eq!(file[26..28], 16u16.to_be_bytes());
+        assert_eq!(file[28..38], [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(&file[38..42], b"SSND");
+        assert_eq!(be(&file[42..46]), 8 + 12);
+        assert_eq!(file[46..54], [0; 8]);
+        assert_eq!(file[54..], [0x00, 0x00, 0x00, 0x01, 0xFF, 0xFF, 0x7F, 0xFF, 0x80, 0x00, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn raw_samples_are_written_as_they_arrive() {
+        let samples = [1, -2, 0x1234];
+
+        let mut writer = AudioWriter::new(Vec::new(), AudioFileFormat::RawS16Be, 1, 8000);
+        writer.write_samples(&samples).unwrap();
+        assert_eq!(writer.finish().unwrap(), [0x00, 0x01, 0xFF, 0xFE, 0x12, 0x34]);
+
+        let mut writer = AudioWriter::new(Vec::new(), AudioFileFormat::RawS16Le, 1, 8000);
+        writer.write_samples(&samples).unwrap();
+        assert_eq!(writer.finish().unwrap(), [0x01, 0x00, 0xFE, 0xFF, 0x34, 0x12]);
+    }
+}
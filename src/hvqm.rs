@@ -1,6 +1,27 @@
+use std::io::Write;
+
+use crate::error::{Error, Result};
+
+/*
+ * Returns the `size` bytes at `offset`, or an error naming the structure
+ * that does not fit in `buf`
+ */
+fn check_size<'a>(structure: &'static str, buf: &'a [u8], offset: usize, size: usize) -> Result<&'a [u8]> {
+    match buf.get(offset..).and_then(|rest| rest.get(..size)) {
+        Some(bytes) => Ok(bytes),
+        None => Err(Error::Truncated {
+            structure,
+            offset: offset as u64,
+            needed: size,
+            available: buf.len().saturating_sub(offset),
+        }),
+    }
+}
+
 /*
  * HVQM2Header : HVQM2 file header
  */
+#[derive(Clone, Debug)]
 pub struct HVQM2Header {
     /* 0x00 */ pub file_version: [u8; 16],
     /* 0x10 */ pub file_size: u32,              /* File size [byte] */
@@ -28,7 +49,57 @@ pub struct HVQM2Header {
 }
 
 impl HVQM2Header {
+    pub const SIZE: usize = 0x3C;
+    pub const FILE_VERSION: [u8; 0x10] = [0x48, 0x56, 0x51, 0x4D, 0x32, 0x20, 0x31, 0x2E, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,];    /* "HVQM2 1.0" */
+
+    /*
+     * Header of a new file with the given video parameters and mono 16-bit
+     * ADPCM audio. The size and count fields are left at zero for the
+     * writer to fill in.
+     * `max_sp_packets` can't be computed from the records (what the player
+     * counts as an SP FIFO packet is unknown), so it has to be given.
+     */
+    pub fn for_video(width: u16, height: u16, h_sampling_rate: u8, v_sampling_rate: u8, video_quantize_shift: u8, usec_per_frame: u32, max_sp_packets: u32) -> HVQM2Header {
+        HVQM2Header {
+            file_version: HVQM2Header::FILE_VERSION,
+            file_size: 0,
+            width,
+            height,
+            h_sampling_rate,
+            v_sampling_rate,
+            y_shiftnum: 0,
+            video_quantize_shift,
+            total_frames: 0,
+            usec_per_frame,
+            max_frame_size: 0,
+            max_sp_packets,
+            audio_format: ADPCM_AUDIO_FORMAT,
+            channels: 1,
+            sample_bits: 16,
+            audio_quantize_step: 0,
+            total_audio_records: 0,
+            samples_per_sec: 0,
+            max_audio_record_size: 0,
+        }
+    }
+
+    /* Parses the structure at the start of `buf` */
+    pub fn parse(buf: &[u8]) -> Result<HVQM2Header> {
+        HVQM2Header::parse_at(buf, 0)
+    }
+
+    /* Panics if `buf` is too short, kept for existing callers */
+    #[deprecated(note = "use `parse`, which returns an error instead of panicking")]
     pub fn new(buf: &[u8]) -> HVQM2Header {
+        HVQM2Header::parse(buf).expect("buffer too short for HVQM2Header")
+    }
+
+    /* Parses the structure at `offset` in `buf`, errors report that offset */
+    pub fn parse_at(buf: &[u8], offset: usize) -> Result<HVQM2Header> {
+        Ok(HVQM2Header::read(check_size("HVQM2Header", buf, offset, HVQM2Header::SIZE)?))
+    }
+
+    fn read(buf: &[u8]) -> HVQM2Header {
         let file_version: [u8; 0x10] = buf[0x00..0x10].try_into().unwrap();
         let file_size = u32::from_be_bytes(buf[0x10..0x14].try_into().unwrap());
 
@@ -54,39 +125,157 @@ impl HVQM2Header {
         let max_audio_record_size = u32::from_be_bytes(buf[0x38..0x3C].try_into().unwrap());
 
         HVQM2Header {
-            file_version: file_version,
-            file_size: file_size,
-            width: width,
-            height: height,
-            h_sampling_rate: h_sampling_rate,
-            v_sampling_rate: v_sampling_rate,
-            y_shiftnum: y_shiftnum,
-            video_quantize_shift: video_quantize_shift,
-            total_frames: total_frames,
-            usec_per_frame: usec_per_frame,
-            max_frame_size: max_frame_size,
-            max_sp_packets: max_sp_packets,
-            audio_format: audio_format,
-            channels: channels,
-            sample_bits: sample_bits,
-            audio_quantize_step: audio_quantize_step,
-            total_audio_records: total_audio_records,
-            samples_per_sec: samples_per_sec,
-            max_audio_record_size: max_audio_record_size,
+            file_version,
+            file_size,
+            width,
+            height,
+            h_sampling_rate,
+            v_sampling_rate,
+            y_shiftnum,
+            video_quantize_shift,
+            total_frames,
+            usec_per_frame,
+            max_frame_size,
+            max_sp_packets,
+            audio_format,
+            channels,
+            sample_bits,
+            audio_quantize_step,
+            total_audio_records,
+            samples_per_sec,
+            max_audio_record_size,
+        }
+    }
+
+    /* Serializes the structure in file order, big-endian */
+    pub fn to_bytes(&self) -> [u8; HVQM2Header::SIZE] {
+        let mut buf = [0u8; HVQM2Header::SIZE];
+
+        buf[0x00..0x10].copy_from_slice(&self.file_version);
+        buf[0x10..0x14].copy_from_slice(&self.file_size.to_be_bytes());
+
+        buf[0x14..0x16].copy_from_slice(&self.width.to_be_bytes());
+        buf[0x16..0x18].copy_from_slice(&self.height.to_be_bytes());
+        buf[0x18] = self.h_sampling_rate;
+        buf[0x19] = self.v_sampling_rate;
+        buf[0x1A] = self.y_shiftnum;
+        buf[0x1B] = self.video_quantize_shift;
+
+        buf[0x1C..0x20].copy_from_slice(&self.total_frames.to_be_bytes());
+        buf[0x20..0x24].copy_from_slice(&self.usec_per_frame.to_be_bytes());
+        buf[0x24..0x28].copy_from_slice(&self.max_frame_size.to_be_bytes());
+        buf[0x28..0x2C].copy_from_slice(&self.max_sp_packets.to_be_bytes());
+
+        buf[0x2C] = self.audio_format;
+        buf[0x2D] = self.channels;
+        buf[0x2E] = self.sample_bits;
+        buf[0x2F] = self.audio_quantize_step;
+
+        buf[0x30..0x34].copy_from_slice(&self.total_audio_records.to_be_bytes());
+        buf[0x34..0x38].copy_from_slice(&self.samples_per_sec.to_be_bytes());
+        buf[0x38..0x3C].copy_from_slice(&self.max_audio_record_size.to_be_bytes());
+        buf
+    }
+
+    pub fn write_to<W: Write>(&self, output: &mut W) -> Result<()> {
+        output.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    /*
+     * Recomputes file_size, total_frames, total_audio_records,
+     * max_frame_size and max_audio_record_size from the records of `buf`,
+     * the whole file starting with this header.
+     *
+     * Zero padding after the last record (see `Records`) is left out of
+     * file_size and its length is returned. Fails if any record can't be
+     * parsed.
+     */
+    pub fn recompute_summary(&mut self, buf: &[u8]) -> Result<u64> {
+        let mut total_frames = 0;
+        let mut total_audio_records = 0;
+        let mut max_frame_size = 0;
+        let mut max_audio_record_size = 0;
+        let mut end = HVQM2Header::SIZE as u64;
+
+        let mut records = Records::new(buf);
+        for entry in &mut records {
+            let entry = entry?;
+            match entry.record.record_type() {
+                RecordType::Audio => {
+                    total_audio_records += 1;
+                    max_audio_record_size = max_audio_record_size.max(entry.header.size);
+                },
+                RecordType::Video => {
+                    total_frames += 1;
+                    max_frame_size = max_frame_size.max(entry.header.size);
+                },
+            }
+            end = entry.offset + HVQM2Record::SIZE as u64 + entry.header.size as u64;
         }
+
+        self.file_size = end as u32;
+        self.total_frames = total_frames;
+        self.total_audio_records = total_audio_records;
+        self.max_frame_size = max_frame_size;
+        self.max_audio_record_size = max_audio_record_size;
+        Ok(records.padding() as u64)
     }
 
     pub fn valid_header(&self) -> bool {
-        let valid: [u8; 0x10] = [0x48, 0x56, 0x51, 0x4D, 0x32, 0x20, 0x31, 0x2E, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,];
+        self.file_version == HVQM2Header::FILE_VERSION
+    }
+
+    pub fn check_magic(&self) -> Result<()> {
+        if self.valid_header() {
+            Ok(())
+        } else {
+            Err(Error::BadMagic { found: self.file_version })
+        }
+    }
 
-        self.file_version == valid
+    pub fn audio_data_format(&self) -> Result<AudioFormat> {
+        AudioFormat::from_u8(self.audio_format)
     }
 
-    pub fn header_str(&self) -> &str {
-        std::str::from_utf8(&self.file_version).unwrap()
+    pub fn header_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.file_version)
     }
 }
 
+/*
+ * AudioFormat : Coding of the audio records (`audio_format` header field)
+ *
+ * Only 1 is known: every known file uses it for its 4-bit ADPCM audio.
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AudioFormat {
+    Adpcm,    /* 4-bit ADPCM decoded to 16-bit samples */
+}
+
+const ADPCM_AUDIO_FORMAT: u8 = 1;
+
+impl AudioFormat {
+    pub fn from_u8(format: u8) -> Result<AudioFormat> {
+        match format {
+            ADPCM_AUDIO_FORMAT => Ok(AudioFormat::Adpcm),
+            _ => Err(Error::UnknownAudioFormat { format }),
+        }
+    }
+
+    /* Header value of the format */
+    pub fn to_u8(self) -> u8 {
+        match self {
+            AudioFormat::Adpcm => ADPCM_AUDIO_FORMAT,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            AudioFormat::Adpcm => "ADPCM",
+        }
+    }
+}
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum RecordType {
@@ -95,11 +284,18 @@ pub enum RecordType {
 }
 
 impl RecordType {
-    pub fn from_u16(t: u16) -> Result<RecordType, ()> {
+    pub fn from_u16(t: u16) -> Result<RecordType> {
         match t {
             0 => Ok(RecordType::Audio),
             1 => Ok(RecordType::Video),
-            _ => Err(()),
+            _ => Err(Error::UnknownRecordType { record_type: t }),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            RecordType::Audio => 0,
+            RecordType::Video => 1,
         }
     }
 }
@@ -114,32 +310,49 @@ pub enum DataFormat {
 }
 
 impl DataFormat {
-    pub fn from_u16(format: u16, record_type: RecordType) -> Result<DataFormat, ()> {
+    pub fn from_u16(format: u16, record_type: RecordType) -> Result<DataFormat> {
         match record_type {
             RecordType::Audio => match format {
                 0 => Ok(DataFormat::AudioKeyframe),
                 1 => Ok(DataFormat::AudioPredict),
-                _ => Err(())
+                _ => Err(Error::UnknownFormat { record_type, format }),
             },
             RecordType::Video => match format {
                 0 => Ok(DataFormat::VideoKeyframe),
                 1 => Ok(DataFormat::VideoPredict),
                 2 => Ok(DataFormat::VideoHold),
-                _ => Err(())
+                _ => Err(Error::UnknownFormat { record_type, format }),
             },
         }
     }
 
-    pub fn to_adpcm_format(&self) -> Result<crate::adpcm::ADPCMFormat, ()> {
+    /* Value of the record header `format` field */
+    pub fn to_u16(self) -> u16 {
+        match self {
+            DataFormat::AudioKeyframe | DataFormat::VideoKeyframe => 0,
+            DataFormat::AudioPredict | DataFormat::VideoPredict => 1,
+            DataFormat::VideoHold => 2,
+        }
+    }
+
+    pub fn record_type(self) -> RecordType {
+        match self {
+            DataFormat::AudioKeyframe | DataFormat::AudioPredict => RecordType::Audio,
+            _ => RecordType::Video,
+        }
+    }
+
+    pub fn to_adpcm_format(self) -> Result<crate::adpcm::ADPCMFormat> {
         match self {
             DataFormat::AudioKeyframe => Ok(crate::adpcm::ADPCMFormat::Reset),
             DataFormat::AudioPredict => Ok(crate::adpcm::ADPCMFormat::Continue),
-            _ => Err(()),
+            _ => Err(Error::UnexpectedFormat { expected: "audio", found: self }),
         }
     }
 }
 
 
+#[derive(Clone, Debug)]
 pub struct HVQM2Record {
     pub r_type: u16,          /* Record type  */
     pub format: u16,          /* Data format  */
@@ -150,50 +363,119 @@ pub struct HVQM2Record {
  * HVQM2Record : Record header (Located directly after HVQM2Header)
  */
 impl HVQM2Record {
+    pub const SIZE: usize = 0x8;
+
+    /* Header of a record of `format` with `size` bytes of data */
+    pub fn for_data(format: DataFormat, size: u32) -> HVQM2Record {
+        HVQM2Record {
+            r_type: format.record_type().to_u16(),
+            format: format.to_u16(),
+            size,
+        }
+    }
+
+    /* Parses the structure at the start of `buf` */
+    pub fn parse(buf: &[u8]) -> Result<HVQM2Record> {
+        HVQM2Record::parse_at(buf, 0)
+    }
+
+    /* Panics if `buf` is too short, kept for existing callers */
+    #[deprecated(note = "use `parse`, which returns an error instead of panicking")]
     pub fn new(buf: &[u8]) -> HVQM2Record {
+        HVQM2Record::parse(buf).expect("buffer too short for HVQM2Record")
+    }
+
+    /* Parses the structure at `offset` in `buf`, errors report that offset */
+    pub fn parse_at(buf: &[u8], offset: usize) -> Result<HVQM2Record> {
+        Ok(HVQM2Record::read(check_size("HVQM2Record", buf, offset, HVQM2Record::SIZE)?))
+    }
+
+    fn read(buf: &[u8]) -> HVQM2Record {
         let r_type = u16::from_be_bytes(buf[0x0..0x2].try_into().unwrap());
         let format = u16::from_be_bytes(buf[0x2..0x4].try_into().unwrap());
         let size = u32::from_be_bytes(buf[0x4..0x8].try_into().unwrap());
 
         HVQM2Record {
-            r_type : r_type,
-            format : format,
-            size : size,
+            r_type,
+            format,
+            size,
         }
     }
 
-    pub fn record_type(&self) -> Result<RecordType, ()> {
+    /* Serializes the structure in file order, big-endian */
+    pub fn to_bytes(&self) -> [u8; HVQM2Record::SIZE] {
+        let mut buf = [0u8; HVQM2Record::SIZE];
+
+        buf[0x0..0x2].copy_from_slice(&self.r_type.to_be_bytes());
+        buf[0x2..0x4].copy_from_slice(&self.format.to_be_bytes());
+        buf[0x4..0x8].copy_from_slice(&self.size.to_be_bytes());
+        buf
+    }
+
+    pub fn write_to<W: Write>(&self, output: &mut W) -> Result<()> {
+        output.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn record_type(&self) -> Result<RecordType> {
         RecordType::from_u16(self.r_type)
     }
 
-    pub fn data_format(&self) -> Result<DataFormat, ()> {
-        match self.record_type() {
-            Err(value) => Err(value),
-            Ok(rec_type) => DataFormat::from_u16(self.format, rec_type)
-        }
+    pub fn data_format(&self) -> Result<DataFormat> {
+        DataFormat::from_u16(self.format, self.record_type()?)
     }
 }
 
 /*
  * HVQM2Audio : Audio header (Follows record header)
  */
+#[derive(Clone, Debug)]
 pub struct HVQM2AudioHeader {
     pub samples: u32,        /* Number of samples (/channels)  */
 }
 
 impl HVQM2AudioHeader {
+    pub const SIZE: usize = 0x4;
+
+    /* Parses the structure at the start of `buf` */
+    pub fn parse(buf: &[u8]) -> Result<HVQM2AudioHeader> {
+        HVQM2AudioHeader::parse_at(buf, 0)
+    }
+
+    /* Panics if `buf` is too short, kept for existing callers */
+    #[deprecated(note = "use `parse`, which returns an error instead of panicking")]
     pub fn new(buf: &[u8]) -> HVQM2AudioHeader {
+        HVQM2AudioHeader::parse(buf).expect("buffer too short for HVQM2AudioHeader")
+    }
+
+    /* Parses the structure at `offset` in `buf`, errors report that offset */
+    pub fn parse_at(buf: &[u8], offset: usize) -> Result<HVQM2AudioHeader> {
+        Ok(HVQM2AudioHeader::read(check_size("HVQM2AudioHeader", buf, offset, HVQM2AudioHeader::SIZE)?))
+    }
+
+    fn read(buf: &[u8]) -> HVQM2AudioHeader {
         let samples = u32::from_be_bytes(buf[0x0..0x4].try_into().unwrap());
 
         HVQM2AudioHeader {
-            samples : samples,
+            samples,
         }
     }
+
+    /* Serializes the structure in file order, big-endian */
+    pub fn to_bytes(&self) -> [u8; HVQM2AudioHeader::SIZE] {
+        self.samples.to_be_bytes()
+    }
+
+    pub fn write_to<W: Write>(&self, output: &mut W) -> Result<()> {
+        output.write_all(&self.to_bytes())?;
+        Ok(())
+    }
 }
 
 /*
  * HVQM2Frame :  Video header  (Follows record header)
  */
+#[derive(Clone, Debug)]
 pub struct HVQM2Frame {
     /* 0x00 */ pub basisnum_offset: [u32; 2],    /* Basis number block (0: brightness, 1: color difference) */
     /* 0x08 */ pub basnumrn_offset: [u32; 2],    /* Basis number cold run (0: brightness, 1: color difference)   */
@@ -203,7 +485,25 @@ pub struct HVQM2Frame {
 }
 
 impl HVQM2Frame {
+    pub const SIZE: usize = 0x34;
+
+    /* Parses the structure at the start of `buf` */
+    pub fn parse(buf: &[u8]) -> Result<HVQM2Frame> {
+        HVQM2Frame::parse_at(buf, 0)
+    }
+
+    /* Panics if `buf` is too short, kept for existing callers */
+    #[deprecated(note = "use `parse`, which returns an error instead of panicking")]
     pub fn new(buf: &[u8]) -> HVQM2Frame {
+        HVQM2Frame::parse(buf).expect("buffer too short for HVQM2Frame")
+    }
+
+    /* Parses the structure at `offset` in `buf`, errors report that offset */
+    pub fn parse_at(buf: &[u8], offset: usize) -> Result<HVQM2Frame> {
+        Ok(HVQM2Frame::read(check_size("HVQM2Frame", buf, offset, HVQM2Frame::SIZE)?))
+    }
+
+    fn read(buf: &[u8]) -> HVQM2Frame {
         let basisnum_offset: [u32; 2] = [u32::from_be_bytes(buf[0x00..0x04].try_into().unwrap()), u32::from_be_bytes(buf[0x04..0x08].try_into().unwrap())];
         let basnumrn_offset: [u32; 2] = [u32::from_be_bytes(buf[0x08..0x0C].try_into().unwrap()), u32::from_be_bytes(buf[0x0C..0x10].try_into().unwrap())];
         let scale_offset: [u32; 3] = [u32::from_be_bytes(buf[0x10..0x14].try_into().unwrap()), u32::from_be_bytes(buf[0x14..0x18].try_into().unwrap()), u32::from_be_bytes(buf[0x18..0x1C].try_into().unwrap())];
@@ -211,18 +511,134 @@ impl HVQM2Frame {
         let dcval_offset: [u32; 3] = [u32::from_be_bytes(buf[0x28..0x2C].try_into().unwrap()), u32::from_be_bytes(buf[0x2C..0x30].try_into().unwrap()), u32::from_be_bytes(buf[0x30..0x34].try_into().unwrap())];
 
         HVQM2Frame {
-            basisnum_offset: basisnum_offset,
-            basnumrn_offset: basnumrn_offset,
-            scale_offset: scale_offset,
-            fixvl_offset: fixvl_offset,
-            dcval_offset: dcval_offset,
+            basisnum_offset,
+            basnumrn_offset,
+            scale_offset,
+            fixvl_offset,
+            dcval_offset,
+        }
+    }
+
+    /* Serializes the structure in file order, big-endian */
+    pub fn to_bytes(&self) -> [u8; HVQM2Frame::SIZE] {
+        let mut buf = [0u8; HVQM2Frame::SIZE];
+
+        let offsets = self.basisnum_offset.iter()
+            .chain(&self.basnumrn_offset)
+            .chain(&self.scale_offset)
+            .chain(&self.fixvl_offset)
+            .chain(&self.dcval_offset);
+        for (bytes, offset) in buf.chunks_exact_mut(4).zip(offsets) {
+            bytes.copy_from_slice(&offset.to_be_bytes());
         }
+        buf
+    }
+
+    pub fn write_to<W: Write>(&self, output: &mut W) -> Result<()> {
+        output.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    /*
+     * Splits the data of a VideoKeyframe or VideoPredict record (`payload`,
+     * starting with this header) into its sections.
+     *
+     * Only section starts are stored, so each section ends at the nearest
+     * start of another section after its own, or at the end of the record.
+     * Sections can be in any order. Sections sharing a start can't all have
+     * data: the last one in header order (the HVQM2Frame fields, then the
+     * key frame or predict frame ones) gets it, the others are empty.
+     *
+     * Fails if a section starts inside the headers or past the record.
+     */
+    pub fn sections<'a>(&self, payload: &'a [u8], format: DataFormat) -> Result<FrameSections<'a>> {
+        let mut offsets = vec![
+            ("basisnum[0]", self.basisnum_offset[0]),
+            ("basisnum[1]", self.basisnum_offset[1]),
+            ("basnumrn[0]", self.basnumrn_offset[0]),
+            ("basnumrn[1]", self.basnumrn_offset[1]),
+            ("scale[0]", self.scale_offset[0]),
+            ("scale[1]", self.scale_offset[1]),
+            ("scale[2]", self.scale_offset[2]),
+            ("fixvl[0]", self.fixvl_offset[0]),
+            ("fixvl[1]", self.fixvl_offset[1]),
+            ("fixvl[2]", self.fixvl_offset[2]),
+            ("dcval[0]", self.dcval_offset[0]),
+            ("dcval[1]", self.dcval_offset[1]),
+            ("dcval[2]", self.dcval_offset[2]),
+        ];
+
+        let headers_size = match format {
+            DataFormat::VideoKeyframe => {
+                let key_frame = HVQM2KeyFrame::parse_at(payload, HVQM2Frame::SIZE)?;
+                offsets.push(("dcrun[0]", key_frame.dcrun_offset[0]));
+                offsets.push(("dcrun[1]", key_frame.dcrun_offset[1]));
+                offsets.push(("dcrun[2]", key_frame.dcrun_offset[2]));
+                HVQM2Frame::SIZE + HVQM2KeyFrame::SIZE
+            },
+            DataFormat::VideoPredict => {
+                let predict_frame = HVQM2PredictFrame::parse_at(payload, HVQM2Frame::SIZE)?;
+                offsets.push(("movevector", predict_frame.movevector_offset));
+                offsets.push(("macroblock", predict_frame.macroblock_offset));
+                HVQM2Frame::SIZE + HVQM2PredictFrame::SIZE
+            },
+            _ => return Err(Error::UnexpectedFormat { expected: "key frame or predicted frame", found: format }),
+        };
+
+        for &(section, offset) in &offsets {
+            if (offset as usize) < headers_size || offset as usize > payload.len() {
+                return Err(Error::OffsetOutOfRange { section, offset: offset as u64, limit: payload.len() as u64 });
+            }
+        }
+
+        let mut slices = Vec::with_capacity(offsets.len());
+        for (i, &(_, offset)) in offsets.iter().enumerate() {
+            let start = offset as usize;
+            let shared = offsets[i + 1..].iter().any(|&(_, other)| other == offset);
+            let end = match shared {
+                true => start,
+                false => offsets.iter().map(|&(_, other)| other as usize).filter(|&other| other > start).min().unwrap_or(payload.len()),
+            };
+            slices.push(&payload[start..end]);
+        }
+
+        let (dcrun, movevector, macroblock): ([&[u8]; 3], &[u8], &[u8]) = match format {
+            DataFormat::VideoKeyframe => ([slices[13], slices[14], slices[15]], &[], &[]),
+            _ => ([&[]; 3], slices[13], slices[14]),
+        };
+
+        Ok(FrameSections {
+            basisnum: [slices[0], slices[1]],
+            basnumrn: [slices[2], slices[3]],
+            scale: [slices[4], slices[5], slices[6]],
+            fixvl: [slices[7], slices[8], slices[9]],
+            dcval: [slices[10], slices[11], slices[12]],
+            dcrun,
+            movevector,
+            macroblock,
+        })
     }
 }
 
+/*
+ * FrameSections : Section data of a video record
+ */
+#[derive(Clone, Debug)]
+pub struct FrameSections<'a> {
+    pub basisnum: [&'a [u8]; 2],    /* Basis number block (0: brightness, 1: color difference) */
+    pub basnumrn: [&'a [u8]; 2],    /* Basis number cold run (0: brightness, 1: color difference) */
+    pub scale: [&'a [u8]; 3],       /* Basis coefficient (0:Y, 1:U, 2:V) */
+    pub fixvl: [&'a [u8]; 3],       /* Fixed length code (0:Y, 1:U, 2:V) */
+    pub dcval: [&'a [u8]; 3],       /* Block DC (0:Y, 1:U, 2:V) */
+    pub dcrun: [&'a [u8]; 3],       /* DC value cold run (key frames only, empty otherwise) */
+    pub movevector: &'a [u8],       /* Movement vector (predicted frames only, empty otherwise) */
+    pub macroblock: &'a [u8],       /* Macro block state flag (predicted frames only, empty otherwise) */
+}
+
 /*
  * HVQM2KeyFrame : Key frame header (Follows the video header)
  */
+#[derive(Clone, Debug)]
 pub struct HVQM2KeyFrame {
     /* 0x0 */ pub dcrun_offset: [u32; 3],    /* DC value cold run (0:Y, 1:U, 2:V) */
     /* 0xC */ pub nest_start_x: u16,        /* Base start position (x coordinate) */
@@ -230,35 +646,460 @@ pub struct HVQM2KeyFrame {
 }
 
 impl HVQM2KeyFrame {
+    pub const SIZE: usize = 0x10;
+
+    /* Parses the structure at the start of `buf` */
+    pub fn parse(buf: &[u8]) -> Result<HVQM2KeyFrame> {
+        HVQM2KeyFrame::parse_at(buf, 0)
+    }
+
+    /* Panics if `buf` is too short, kept for existing callers */
+    #[deprecated(note = "use `parse`, which returns an error instead of panicking")]
     pub fn new(buf: &[u8]) -> HVQM2KeyFrame {
+        HVQM2KeyFrame::parse(buf).expect("buffer too short for HVQM2KeyFrame")
+    }
+
+    /* Parses the structure at `offset` in `buf`, errors report that offset */
+    pub fn parse_at(buf: &[u8], offset: usize) -> Result<HVQM2KeyFrame> {
+        Ok(HVQM2KeyFrame::read(check_size("HVQM2KeyFrame", buf, offset, HVQM2KeyFrame::SIZE)?))
+    }
+
+    fn read(buf: &[u8]) -> HVQM2KeyFrame {
         let dcrun_offset: [u32; 3] = [u32::from_be_bytes(buf[0x00..0x04].try_into().unwrap()), u32::from_be_bytes(buf[0x04..0x08].try_into().unwrap()), u32::from_be_bytes(buf[0x08..0x0C].try_into().unwrap())];
         let nest_start_x: u16 = u16::from_be_bytes(buf[0x0C..0x0E].try_into().unwrap());
         let nest_start_y: u16 = u16::from_be_bytes(buf[0x0E..0x10].try_into().unwrap());
 
         HVQM2KeyFrame {
-            dcrun_offset: dcrun_offset,
-            nest_start_x: nest_start_x,
-            nest_start_y: nest_start_y,
+            dcrun_offset,
+            nest_start_x,
+            nest_start_y,
         }
     }
+
+    /* Serializes the structure in file order, big-endian */
+    pub fn to_bytes(&self) -> [u8; HVQM2KeyFrame::SIZE] {
+        let mut buf = [0u8; HVQM2KeyFrame::SIZE];
+
+        buf[0x00..0x04].copy_from_slice(&self.dcrun_offset[0].to_be_bytes());
+        buf[0x04..0x08].copy_from_slice(&self.dcrun_offset[1].to_be_bytes());
+        buf[0x08..0x0C].copy_from_slice(&self.dcrun_offset[2].to_be_bytes());
+        buf[0x0C..0x0E].copy_from_slice(&self.nest_start_x.to_be_bytes());
+        buf[0x0E..0x10].copy_from_slice(&self.nest_start_y.to_be_bytes());
+        buf
+    }
+
+    pub fn write_to<W: Write>(&self, output: &mut W) -> Result<()> {
+        output.write_all(&self.to_bytes())?;
+        Ok(())
+    }
 }
 
 /*
 * HVQM2PredictFrame : Predict frame header (Follows video header)
 */
+#[derive(Clone, Debug)]
 pub struct HVQM2PredictFrame {
     /* 0x0 */ pub movevector_offset: u32,    /* Movement vector */
     /* 0x4 */ pub macroblock_offset: u32,    /* Macro block state flag */
 }
 
 impl HVQM2PredictFrame {
+    pub const SIZE: usize = 0x8;
+
+    /* Parses the structure at the start of `buf` */
+    pub fn parse(buf: &[u8]) -> Result<HVQM2PredictFrame> {
+        HVQM2PredictFrame::parse_at(buf, 0)
+    }
+
+    /* Panics if `buf` is too short, kept for existing callers */
+    #[deprecated(note = "use `parse`, which returns an error instead of panicking")]
     pub fn new(buf: &[u8]) -> HVQM2PredictFrame {
+        HVQM2PredictFrame::parse(buf).expect("buffer too short for HVQM2PredictFrame")
+    }
+
+    /* Parses the structure at `offset` in `buf`, errors report that offset */
+    pub fn parse_at(buf: &[u8], offset: usize) -> Result<HVQM2PredictFrame> {
+        Ok(HVQM2PredictFrame::read(check_size("HVQM2PredictFrame", buf, offset, HVQM2PredictFrame::SIZE)?))
+    }
+
+    fn read(buf: &[u8]) -> HVQM2PredictFrame {
         let movevector_offset: u32 = u32::from_be_bytes(buf[0x00..0x04].try_into().unwrap());
         let macroblock_offset: u32 = u32::from_be_bytes(buf[0x04..0x08].try_into().unwrap());
 
         HVQM2PredictFrame {
-            movevector_offset: movevector_offset,
-            macroblock_offset: macroblock_offset,
+            movevector_offset,
+            macroblock_offset,
         }
     }
+
+    /* Serializes the structure in file order, big-endian */
+    pub fn to_bytes(&self) -> [u8; HVQM2PredictFrame::SIZE] {
+        let mut buf = [0u8; HVQM2PredictFrame::SIZE];
+
+        buf[0x00..0x04].copy_from_slice(&self.movevector_offset.to_be_bytes());
+        buf[0x04..0x08].copy_from_slice(&self.macroblock_offset.to_be_bytes());
+        buf
+    }
+
+    pub fn write_to<W: Write>(&self, output: &mut W) -> Result<()> {
+        output.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+}
+
+impl TryFrom<&[u8]> for HVQM2Header {
+    type Error = Error;
+
+    fn try_from(buf: &[u8]) -> Result<HVQM2Header> {
+        HVQM2Header::parse(buf)
+    }
+}
+
+impl TryFrom<&[u8]> for HVQM2Record {
+    type Error = Error;
+
+    fn try_from(buf: &[u8]) -> Result<HVQM2Record> {
+        HVQM2Record::parse(buf)
+    }
+}
+
+impl TryFrom<&[u8]> for HVQM2AudioHeader {
+    type Error = Error;
+
+    fn try_from(buf: &[u8]) -> Result<HVQM2AudioHeader> {
+        HVQM2AudioHeader::parse(buf)
+    }
+}
+
+impl TryFrom<&[u8]> for HVQM2Frame {
+    type Error = Error;
+
+    fn try_from(buf: &[u8]) -> Result<HVQM2Frame> {
+        HVQM2Frame::parse(buf)
+    }
+}
+
+impl TryFrom<&[u8]> for HVQM2KeyFrame {
+    type Error = Error;
+
+    fn try_from(buf: &[u8]) -> Result<HVQM2KeyFrame> {
+        HVQM2KeyFrame::parse(buf)
+    }
+}
+
+impl TryFrom<&[u8]> for HVQM2PredictFrame {
+    type Error = Error;
+
+    fn try_from(buf: &[u8]) -> Result<HVQM2PredictFrame> {
+        HVQM2PredictFrame::parse(buf)
+    }
+}
+
+/*
+ * Record : Typed view of one record, borrowing its data
+ */
+#[derive(Clone, Debug)]
+pub enum Record<'a> {
+    Audio {
+        format: DataFormat,           /* AudioKeyframe or AudioPredict */
+        header: HVQM2AudioHeader,
+        data: &'a [u8],               /* Audio data following the audio header */
+    },
+    VideoKeyframe {
+        frame: HVQM2Frame,
+        key_frame: HVQM2KeyFrame,
+        payload: &'a [u8],            /* Whole record data, section offsets are relative to it */
+    },
+    VideoPredict {
+        frame: HVQM2Frame,
+        predict_frame: HVQM2PredictFrame,
+        payload: &'a [u8],            /* Whole record data, section offsets are relative to it */
+    },
+    VideoHold,
+}
+
+impl<'a> Record<'a> {
+    /* Parses the sub-headers of a record whose data is `data` */
+    pub fn parse(header: &HVQM2Record, data: &'a [u8]) -> Result<Record<'a>> {
+        match header.data_format()? {
+            format @ (DataFormat::AudioKeyframe | DataFormat::AudioPredict) => Ok(Record::Audio {
+                format,
+                header: HVQM2AudioHeader::parse(data)?,
+                data: &data[HVQM2AudioHeader::SIZE..],
+            }),
+            DataFormat::VideoKeyframe => Ok(Record::VideoKeyframe {
+                frame: HVQM2Frame::parse(data)?,
+                key_frame: HVQM2KeyFrame::parse_at(data, HVQM2Frame::SIZE)?,
+                payload: data,
+            }),
+            DataFormat::VideoPredict => Ok(Record::VideoPredict {
+                frame: HVQM2Frame::parse(data)?,
+                predict_frame: HVQM2PredictFrame::parse_at(data, HVQM2Frame::SIZE)?,
+                payload: data,
+            }),
+            DataFormat::VideoHold => Ok(Record::VideoHold),
+        }
+    }
+
+    pub fn data_format(&self) -> DataFormat {
+        match self {
+            Record::Audio { format, .. } => *format,
+            Record::VideoKeyframe { .. } => DataFormat::VideoKeyframe,
+            Record::VideoPredict { .. } => DataFormat::VideoPredict,
+            Record::VideoHold => DataFormat::VideoHold,
+        }
+    }
+
+    pub fn record_type(&self) -> RecordType {
+        match self {
+            Record::Audio { .. } => RecordType::Audio,
+            _ => RecordType::Video,
+        }
+    }
+
+    /* Section data of a key frame or predicted frame record */
+    pub fn sections(&self) -> Result<FrameSections<'a>> {
+        match self {
+            Record::VideoKeyframe { frame, payload, .. } => frame.sections(payload, DataFormat::VideoKeyframe),
+            Record::VideoPredict { frame, payload, .. } => frame.sections(payload, DataFormat::VideoPredict),
+            _ => Err(Error::UnexpectedFormat { expected: "key frame or predicted frame", found: self.data_format() }),
+        }
+    }
+
+    /* Video record data as expected by `VideoDecoder::decode` (empty for holds) */
+    pub fn video_payload(&self) -> &'a [u8] {
+        match self {
+            Record::VideoKeyframe { payload, .. } | Record::VideoPredict { payload, .. } => payload,
+            _ => &[],
+        }
+    }
+}
+
+/*
+ * RecordEntry : A record yielded by `Records`
+ */
+#[derive(Clone, Debug)]
+pub struct RecordEntry<'a> {
+    pub index: u32,          /* Position of the record in the file */
+    pub offset: u64,         /* File offset of the record header */
+    pub header: HVQM2Record,
+    pub data: &'a [u8],      /* Record data following the record header */
+    pub record: Record<'a>,
+}
+
+/*
+ * Records : Iterator over the records of a whole file held in memory
+ *
+ * Stops at the end of the buffer, or after the first error. Files can be
+ * padded with zeros after the last record (no record is all zeros, an
+ * audio record needs its audio header): when only zeros are left, they
+ * are taken as padding and end the records.
+ */
+pub struct Records<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    index: u32,
+    failed: bool,
+    padding: usize,    /* Zero bytes found after the last record */
+}
+
+impl<'a> Records<'a> {
+    /* `buf` is the whole file, starting with its HVQM2Header */
+    pub fn new(buf: &'a [u8]) -> Records<'a> {
+        Records {
+            buf,
+            offset: HVQM2Header::SIZE,
+            index: 0,
+            failed: false,
+            padding: 0,
+        }
+    }
+
+    /* Length of the zero padding after the last record, once it was reached */
+    pub fn padding(&self) -> usize {
+        self.padding
+    }
+
+    fn parse_next(&self) -> Result<(RecordEntry<'a>, usize)> {
+        let header = HVQM2Record::parse_at(self.buf, self.offset)?;
+        let data_offset = self.offset + HVQM2Record::SIZE;
+        let data = check_size("record data", self.buf, data_offset, header.size as usize)?;
+        let record = Record::parse(&header, data)?;
+
+        let entry = RecordEntry {
+            index: self.index,
+            offset: self.offset as u64,
+            header,
+            data,
+            record,
+        };
+        Ok((entry, data_offset + data.len()))
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<RecordEntry<'a>>;
+
+    fn next(&mut self) -> Option<Result<RecordEntry<'a>>> {
+        if self.failed || self.offset >= self.buf.len() {
+            return None;
+        }
+        if self.buf[self.offset..].iter().all(|&b| b == 0) {
+            self.padding = self.buf.len() - self.offset;
+            self.offset = self.buf.len();
+            return None;
+        }
+
+        match self.parse_next() {
+            Ok((entry, next_offset)) => {
+                self.offset = next_offset;
+                self.index += 1;
+                Some(Ok(entry))
+            },
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err.in_record(self.index, self.offset as u64)))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_known_audio_formats_are_accepted() {
+        assert_eq!(AudioFormat::from_u8(1).unwrap(), AudioFormat::Adpcm);
+        for format in [0, 2, 0xFF] {
+            assert!(matches!(AudioFormat::from_u8(format), Err(Error::UnknownAudioFormat { .. })));
+        }
+    }
+
+    /* Header followed by one audio record of `samples` samples in `data_size` bytes */
+    fn file_with_audio_record(samples: u32, data_size: u32) -> Vec<u8> {
+        let mut file = HVQM2Header::for_video(16, 16, 2, 2, 4, 33333, 1).to_bytes().to_vec();
+        file.extend(HVQM2Record::for_data(DataFormat::AudioKeyframe, data_size).to_bytes());
+        file.extend(HVQM2AudioHeader { samples }.to_bytes());
+        file.resize(file.len() + data_size as usize - HVQM2AudioHeader::SIZE, 0x11);
+        file
+    }
+
+    #[test]
+    fn recompute_summary_leaves_out_zero_padding() {
+        let file = file_with_audio_record(4, 8);
+        let mut header = HVQM2Header::parse(&file).unwrap();
+        assert_eq!(header.recompute_summary(&file).unwrap(), 0);
+        assert_eq!(header.file_size as usize, file.len());
+        assert_eq!(header.total_audio_records, 1);
+
+        /* Long enough to parse as an empty audio record */
+        let mut padded = file.clone();
+        padded.resize(file.len() + 16, 0);
+        let mut header = HVQM2Header::parse(&padded).unwrap();
+        assert_eq!(header.recompute_summary(&padded).unwrap(), 16);
+        assert_eq!(header.file_size as usize, file.len());
+        assert_eq!(header.total_audio_records, 1);
+
+        let mut garbage = file.clone();
+        garbage.extend([0, 0, 0, 0, 0xFF, 0, 0, 0]);
+        assert!(header.recompute_summary(&garbage).is_err());
+    }
+
+    /* Key frame record data with the given section starts, followed by bytes 0, 1, 2... up to `size` */
+    fn key_frame(offsets: [u32; 16], size: usize) -> Vec<u8> {
+        let frame = HVQM2Frame {
+            basisnum_offset: [offsets[0], offsets[1]],
+            basnumrn_offset: [offsets[2], offsets[3]],
+            scale_offset: [offsets[4], offsets[5], offsets[6]],
+            fixvl_offset: [offsets[7], offsets[8], offsets[9]],
+            dcval_offset: [offsets[10], offsets[11], offsets[12]],
+        };
+        let key_frame = HVQM2KeyFrame { dcrun_offset: [offsets[13], offsets[14], offsets[15]], nest_start_x: 0, nest_start_y: 0 };
+
+        let mut data = frame.to_bytes().to_vec();
+        data.extend(key_frame.to_bytes());
+        let headers_size = data.len();
+        data.extend((headers_size..size).map(|i| i as u8));
+        data
+    }
+
+    const HEADERS: u32 = (HVQM2Frame::SIZE + HVQM2KeyFrame::SIZE) as u32;
+
+    #[test]
+    fn sections_end_at_the_next_start_in_any_order() {
+        /* dcrun first, then dcval in reverse, then the rest in header order; fixvl[2] is last */
+        let h = HEADERS;
+        let offsets = [h + 12, h + 13, h + 14, h + 15, h + 16, h + 17, h + 18, h + 19, h + 20, h + 21, h + 10, h + 8, h + 6, h, h + 2, h + 4];
+        let data = key_frame(offsets, h as usize + 30);
+        let frame = HVQM2Frame::parse(&data).unwrap();
+        let sections = frame.sections(&data, DataFormat::VideoKeyframe).unwrap();
+
+        let at = |start: u32, end: u32| &data[start as usize..end as usize];
+        assert_eq!(sections.dcrun, [at(h, h + 2), at(h + 2, h + 4), at(h + 4, h + 6)]);
+        assert_eq!(sections.dcval, [at(h + 10, h + 12), at(h + 8, h + 10), at(h + 6, h + 8)]);
+        assert_eq!(sections.basisnum, [at(h + 12, h + 13), at(h + 13, h + 14)]);
+        assert_eq!(sections.fixvl, [at(h + 19, h + 20), at(h + 20, h + 21), at(h + 21, h + 30)]);
+    }
+
+    #[test]
+    fn sections_sharing_a_start_are_empty_but_the_last() {
+        let h = HEADERS;
+        let mut offsets = [h; 16];
+        offsets[7] = h + 4;    /* fixvl[0] */
+        offsets[8] = h + 4;    /* fixvl[1] */
+        let data = key_frame(offsets, h as usize + 10);
+        let frame = HVQM2Frame::parse(&data).unwrap();
+        let sections = frame.sections(&data, DataFormat::VideoKeyframe).unwrap();
+
+        /* dcrun[2] is the last section at h, fixvl[1] the last one at h + 4 */
+        assert_eq!(sections.dcrun[2], &data[h as usize..h as usize + 4]);
+        assert_eq!(sections.fixvl[1], &data[h as usize + 4..]);
+        assert!(sections.fixvl[0].is_empty());
+        assert!(sections.dcrun[..2].iter().chain(&sections.basisnum).chain(&sections.scale).chain(&sections.dcval).all(|section| section.is_empty()));
+    }
+
+    #[test]
+    fn sections_outside_of_the_record_are_rejected() {
+        let size = HEADERS as usize + 8;
+        for bad in [0, HEADERS - 1, size as u32 + 1, u32::MAX] {
+            let mut offsets = [HEADERS; 16];
+            offsets[5] = bad;
+            let data = key_frame(offsets, size);
+            let frame = HVQM2Frame::parse(&data).unwrap();
+            match frame.sections(&data, DataFormat::VideoKeyframe) {
+                Err(Error::OffsetOutOfRange { section, offset, limit }) => assert_eq!((section, offset, limit), ("scale[1]", bad as u64, size as u64)),
+                other => panic!("offset 0x{bad:X}: {other:?}"),
+            }
+        }
+
+        /* A section can start at the very end, empty */
+        let mut offsets = [HEADERS; 16];
+        offsets[15] = size as u32;
+        let data = key_frame(offsets, size);
+        let sections = HVQM2Frame::parse(&data).unwrap().sections(&data, DataFormat::VideoKeyframe).unwrap();
+        assert!(sections.dcrun[2].is_empty());
+        assert_eq!(sections.dcrun[1], &data[HEADERS as usize..]);
+    }
+
+    #[test]
+    fn records_stop_at_zero_padding() {
+        let file = file_with_audio_record(4, 8);
+        for padding in [1, 8, 64] {
+            let mut padded = file.clone();
+            padded.resize(file.len() + padding, 0);
+
+            let mut records = Records::new(&padded);
+            assert_eq!((&mut records).map(|entry| entry.unwrap().index).collect::<Vec<_>>(), [0]);
+            assert_eq!(records.padding(), padding);
+        }
+
+        let mut garbage = file.clone();
+        garbage.extend([0; 12]);
+        garbage.push(1);
+        let mut records = Records::new(&garbage);
+        assert!(records.next().unwrap().is_ok());
+        assert!(records.next().unwrap().is_err());
+        assert_eq!(records.padding(), 0);
+    }
 }
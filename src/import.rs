@@ -0,0 +1,246 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::color;
+use crate::error::{Error, Result};
+use crate::video::Picture;
+
+/*
+ * SourceFrame : A full resolution YUV image (one chroma sample per pixel)
+ * to be encoded
+ */
+pub struct SourceFrame {
+    pub width: usize,
+    pub height: usize,
+    pub y: Vec<u8>,
+    pub u: Vec<u8>,
+    pub v: Vec<u8>,
+}
+
+impl SourceFrame {
+    /* Converts packed 8-bit RGB */
+    pub fn from_rgb(width: usize, height: usize, rgb: &[u8]) -> SourceFrame {
+        let mut frame = SourceFrame {
+            width,
+            height,
+            y: Vec::with_capacity(width * height),
+            u: Vec::with_capacity(width * height),
+            v: Vec::with_capacity(width * height),
+        };
+
+        for pixel in rgb.chunks_exact(3).take(width * height) {
+            let [y, u, v] = color::rgb_to_yuv([pixel[0], pixel[1], pixel[2]]);
+            frame.y.push(y);
+            frame.u.push(u);
+            frame.v.push(v);
+        }
+        frame
+    }
+
+    /*
+     * Subsamples the chroma to `h_sampling_rate` x `v_sampling_rate` pixels
+     * (averaging them) into a picture of planes rounded up to whole
+     * macroblocks. The padding repeats the last row and column.
+     */
+    pub fn to_picture(&self, h_sampling_rate: usize, v_sampling_rate: usize) -> Picture {
+        let mut picture = Picture::new(self.width, self.height, h_sampling_rate, v_sampling_rate);
+        let sample = |plane: &[u8], x: usize, y: usize| plane[y.min(self.height - 1) * self.width + x.min(self.width - 1)] as u32;
+
+        for y in 0..picture.y.height {
+            for x in 0..picture.y.width {
+                picture.y.set(x, y, sample(&self.y, x, y) as u8);
+            }
+        }
+
+        let area = (h_sampling_rate * v_sampling_rate) as u32;
+        for cy in 0..picture.u.height {
+            for cx in 0..picture.u.width {
+                let (mut u, mut v) = (0, 0);
+                for j in 0..v_sampling_rate {
+                    for i in 0..h_sampling_rate {
+                        u += sample(&self.u, cx * h_sampling_rate + i, cy * v_sampling_rate + j);
+                        v += sample(&self.v, cx * h_sampling_rate + i, cy * v_sampling_rate + j);
+                    }
+                }
+                picture.u.set(cx, cy, ((u + area / 2) / area) as u8);
+                picture.v.set(cx, cy, ((v + area / 2) / area) as u8);
+            }
+        }
+
+        picture
+    }
+}
+
+/*
+ * Reads a PNG image of any color type and bit depth (alpha is ignored)
+ */
+pub fn read_png(path: &Path) -> Result<SourceFrame> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    let pixels = &buf[..info.buffer_size()];
+
+    let rgb: Vec<u8> = match info.color_type {
+        png::ColorType::Rgb => pixels.to_vec(),
+        png::ColorType::Rgba => pixels.chunks_exact(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&value| [value; 3]).collect(),
+        png::ColorType::GrayscaleAlpha => pixels.chunks_exact(2).flat_map(|pixel| [pixel[0]; 3]).collect(),
+        /* Palettes are expanded by `normalize_to_color8` */
+        png::ColorType::Indexed => unreachable!(),
+    };
+
+    Ok(SourceFrame::from_rgb(info.width as usize, info.height as usize, &rgb))
+}
+
+/*
+ * Y4mReader : Reads the frames of a YUV4MPEG2 stream
+ *
+ * 4:2:0, 4:2:2, 4:4:4 and monochrome 8-bit streams are supported. The
+ * chroma is repeated up to full resolution. Samples are taken to be in
+ * the video (16-235) range unless the stream says XCOLORRANGE=FULL, and
+ * are expanded to the full range the decoder uses.
+ */
+pub struct Y4mReader<R: Read> {
+    input: BufReader<R>,
+    width: usize,
+    height: usize,
+    frame_rate: (u32, u32),    /* Frames per second, as a fraction */
+    chroma: Option<(usize, usize)>,    /* Chroma subsampling, `None` for monochrome */
+    full_range: bool,
+}
+
+impl<R: Read> Y4mReader<R> {
+    pub fn new(input: R) -> Result<Y4mReader<R>> {
+        let mut input = BufReader::new(input);
+        let line = read_line(&mut input)?.ok_or(Error::InvalidY4m { reason: "empty stream" })?;
+
+        let mut params = line.split(' ');
+        if params.next() != Some("YUV4MPEG2") {
+            return Err(Error::InvalidY4m { reason: "missing YUV4MPEG2 signature" });
+        }
+
+        let mut width = None;
+        let mut height = None;
+        let mut frame_rate = (30, 1);
+        let mut chroma = Some((2, 2));
+        let mut full_range = false;
+
+        for param in params.filter(|param| !param.is_empty()) {
+            let (tag, value) = param.split_at(1);
+            match tag {
+                "W" => width = value.parse().ok(),
+                "H" => height = value.parse().ok(),
+                "F" => {
+                    let (num, den) = value.split_once(':').ok_or(Error::InvalidY4m { reason: "bad frame rate" })?;
+                    frame_rate = match (num.parse(), den.parse()) {
+                        (Ok(num), Ok(den)) if num > 0 && den > 0 => (num, den),
+                        _ => return Err(Error::InvalidY4m { reason: "bad frame rate" }),
+                    };
+                },
+                "C" => chroma = match value {
+                    "420" | "420jpeg" | "420paldv" | "420mpeg2" => Some((2, 2)),
+                    "422" => Some((2, 1)),
+                    "444" => Some((1, 1)),
+                    "mono" => None,
+                    _ => return Err(Error::InvalidY4m { reason: "unsupported color space" }),
+                },
+                "X" if value == "COLORRANGE=FULL" => full_range = true,
+                _ => (),
+            }
+        }
+
+        match (width, height) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => Ok(Y4mReader {
+                input,
+                width,
+                height,
+                frame_rate,
+                chroma,
+                full_range,
+            }),
+            _ => Err(Error::InvalidY4m { reason: "missing frame size" }),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /* Frames per second as (numerator, denominator) */
+    pub fn frame_rate(&self) -> (u32, u32) {
+        self.frame_rate
+    }
+
+    /* Reads the next frame, or returns `None` at the end of the stream */
+    pub fn next_frame(&mut self) -> Result<Option<SourceFrame>> {
+        let Some(line) = read_line(&mut self.input)? else {
+            return Ok(None);
+        };
+        if line.split(' ').next() != Some("FRAME") {
+            return Err(Error::InvalidY4m { reason: "missing FRAME marker" });
+        }
+
+        let size = self.width * self.height;
+        let mut y = vec![0; size];
+        self.input.read_exact(&mut y)?;
+
+        let (u, v) = match self.chroma {
+            Some((h_sampling_rate, v_sampling_rate)) => {
+                let chroma_width = self.width.div_ceil(h_sampling_rate);
+                let chroma_height = self.height.div_ceil(v_sampling_rate);
+                let mut u = vec![0; chroma_width * chroma_height];
+                let mut v = vec![0; chroma_width * chroma_height];
+                self.input.read_exact(&mut u)?;
+                self.input.read_exact(&mut v)?;
+
+                let upsample = |plane: &[u8]| -> Vec<u8> {
+                    (0..size).map(|i| plane[(i / self.width / v_sampling_rate) * chroma_width + (i % self.width) / h_sampling_rate]).collect()
+                };
+                (upsample(&u), upsample(&v))
+            },
+            None => (vec![0x80; size], vec![0x80; size]),
+        };
+
+        let mut frame = SourceFrame {
+            width: self.width,
+            height: self.height,
+            y,
+            u,
+            v,
+        };
+
+        if !self.full_range {
+            /* Y: 16..235 -> 0..255, chroma: 16..240 -> 0..255 (around 128) */
+            let scale = |value: u8, zero: i32, range: i32| ((value as i32 - zero) * 255 + range / 2).div_euclid(range);
+            for value in frame.y.iter_mut() {
+                *value = scale(*value, 16, 219).clamp(0, 255) as u8;
+            }
+            for value in frame.u.iter_mut().chain(frame.v.iter_mut()) {
+                *value = (scale(*value, 0x80, 224) + 0x80).clamp(0, 255) as u8;
+            }
+        }
+
+        Ok(Some(frame))
+    }
+}
+
+/* Reads a header line without its line feed, `None` at the end of the stream */
+fn read_line<R: Read>(input: &mut BufReader<R>) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    if input.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(Error::InvalidY4m { reason: "truncated header" });
+    }
+
+    String::from_utf8(line).map(Some).map_err(|_| Error::InvalidY4m { reason: "header is not text" })
+}
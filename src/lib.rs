@@ -0,0 +1,42 @@
+//! Decoder for HVQM2, the video format of the Nintendo 64 HVQM2 library.
+//!
+//! `hvqm` parses the container, `demux` streams its records and `mux`
+//! writes them back. `seek` indexes the key frames to decode from any
+//! frame. `audio` (on top of `adpcm`) and `video` decode the audio and
+//! video records, `color` converts decoded pictures to framebuffer pixels
+//! and `export` writes pictures and samples to image and audio files;
+//! `session` runs a whole file through them.
+//! `validate` checks a whole file for inconsistencies and `edit` rewrites
+//! parts of it. `import` reads source images and `encode` codes them into
+//! new video records, within a bitrate with `rate`.
+//! Failures are reported as `error::Error`.
+//!
+//! The container and audio are read as documented by the SDK headers. The
+//! video bitstream inside key and predicted frames is a reconstruction
+//! that has not been checked against the SDK or real N64 files (see
+//! `video::VideoDecoder`), and neither has the color conversion; `encode`
+//! writes that same layout. `video`, `color`, `import`, `encode`, `rate`,
+//! `seek::Player` and the picture export are only built with the
+//! `experimental-video` feature.
+
+pub mod adpcm;
+pub mod audio;
+#[cfg(feature = "experimental-video")]
+pub mod color;
+pub mod demux;
+pub mod edit;
+#[cfg(feature = "experimental-video")]
+pub mod encode;
+pub mod error;
+pub mod export;
+pub mod hvqm;
+#[cfg(feature = "experimental-video")]
+pub mod import;
+pub mod mux;
+#[cfg(feature = "experimental-video")]
+pub mod rate;
+pub mod seek;
+pub mod session;
+pub mod validate;
+#[cfg(feature = "experimental-video")]
+pub mod video;
@@ -1,203 +1,1082 @@
-use std::{fs::File, io::{BufReader, Read, BufWriter, Write}};
-use clap::Parser;
+use std::{fs::File, io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, process::ExitCode};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
 
-mod hvqm;
-mod adpcm;
+use hvqm2_dec::{adpcm, audio, edit, export, hvqm, seek, validate};
+#[cfg(feature = "experimental-video")]
+use hvqm2_dec::{color, encode, import, rate, video};
+use hvqm2_dec::demux::{DemuxedRecord, Demuxer};
+use hvqm2_dec::error::Result;
+use hvqm2_dec::session::Session;
 
+#[cfg(feature = "experimental-video")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+enum OutputPixelFormat {
+    /// N64 16-bit RGBA (5 bits per component)
+    Rgba5551,
+    /// 32-bit RGBA (8 bits per component)
+    Rgba8888,
+}
+
+#[cfg(feature = "experimental-video")]
+impl OutputPixelFormat {
+    fn to_pixel_format(self) -> color::PixelFormat {
+        match self {
+            OutputPixelFormat::Rgba5551 => color::PixelFormat::Rgba5551,
+            OutputPixelFormat::Rgba8888 => color::PixelFormat::Rgba8888,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+enum OutputAudioFormat {
+    /// RIFF WAVE, 16-bit little-endian
+    Wav,
+    /// AIFF, 16-bit big-endian
+    Aiff,
+    /// Raw 16-bit big-endian samples
+    S16be,
+    /// Raw 16-bit little-endian samples
+    S16le,
+}
+
+impl OutputAudioFormat {
+    fn to_audio_file_format(self) -> export::AudioFileFormat {
+        match self {
+            OutputAudioFormat::Wav => export::AudioFileFormat::Wav,
+            OutputAudioFormat::Aiff => export::AudioFileFormat::Aiff,
+            OutputAudioFormat::S16be => export::AudioFileFormat::RawS16Be,
+            OutputAudioFormat::S16le => export::AudioFileFormat::RawS16Le,
+        }
+    }
+}
+
+#[cfg(feature = "experimental-video")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+enum Sampling {
+    /// Chroma at half the horizontal resolution
+    Yuv422,
+    /// Chroma at half the horizontal and vertical resolution
+    Yuv411,
+}
+
+#[cfg(feature = "experimental-video")]
+impl Sampling {
+    /* (h_sampling_rate, v_sampling_rate) */
+    fn rates(self) -> (u8, u8) {
+        match self {
+            Sampling::Yuv422 => (2, 1),
+            Sampling::Yuv411 => (2, 2),
+        }
+    }
+}
+
+/// Decoder for HVQM2 video files of the Nintendo 64 (video decoding and encoding, built with the experimental-video feature, use an unverified reconstruction of the bitstream)
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    /// Input HVQM file
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Print the header, records and summary as JSON instead of text
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the file header
+    Info {
+        #[command(flatten)]
+        input: InputArgs,
+    },
+
+    /// Print the header of every record, without decoding them
+    Records {
+        #[command(flatten)]
+        input: InputArgs,
+    },
+
+    /// List the key frames with the audio position and ADPCM state to resume from
+    Keyframes {
+        #[command(flatten)]
+        input: InputArgs,
+    },
+
+    /// Check the header against the records and the layout of every record, reporting every problem
+    Validate {
+        #[command(flatten)]
+        input: InputArgs,
+    },
+
+    /// Recompute the size and count fields of the header from the records and write a corrected file
+    FixHeader {
+        /// Input HVQM file ("-" reads from standard input)
+        input: String,
+
+        /// Corrected file to write
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Keep the zero padding after the last record (counted in file_size) instead of leaving it out
+        #[arg(long)]
+        keep_padding: bool,
+    },
+
+    /// Re-encode the audio records from a WAV file, keeping the video records
+    ReplaceAudio {
+        /// Input HVQM file ("-" reads from standard input)
+        input: String,
+
+        /// WAV file with the new audio
+        #[arg(long)]
+        wav: PathBuf,
+
+        /// HVQM file to write
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    #[cfg(feature = "experimental-video")]
+    /// Encode a directory of PNG files (in name order) or a YUV4MPEG2 file into a new HVQM file
+    Encode {
+        /// Directory of PNG files or .y4m file
+        input: PathBuf,
+
+        /// HVQM file to write
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Frames per second (default: the Y4M frame rate, or 30)
+        #[arg(long, value_parser = parse_fps)]
+        fps: Option<f64>,
+
+        /// Code a key frame every this many frames (0: only the first frame)
+        #[arg(long, default_value_t = encode::DEFAULT_KEYFRAME_INTERVAL)]
+        keyframe_interval: u32,
+
+        /// Chroma subsampling
+        #[arg(long, value_enum, default_value_t = Sampling::Yuv422)]
+        sampling: Sampling,
+
+        /// Video quantize shift stored in the header (basis scales are divided by 2^shift)
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(0..=video::MAX_QUANTIZE_SHIFT as i64))]
+        quantize_shift: u8,
+
+        /// Largest squared error of a 4x4 block before more bases are spent on it
+        #[arg(long, default_value_t = encode::DEFAULT_BLOCK_ERROR_LIMIT)]
+        block_error_limit: u32,
+
+        /// WAV file to encode as the audio track
+        #[arg(long)]
+        wav: Option<PathBuf>,
+
+        /// Average bitrate of the whole file in bits per second, lowering quality and dropping frames to stay under it
+        #[arg(long)]
+        bitrate: Option<u32>,
+
+        /// Largest video record in bytes
+        #[arg(long)]
+        max_frame_size: Option<u32>,
+
+        /// Maximum number of SP FIFO packets stored in the header. It can't be computed, take it from a file made for the same player (see `info`)
+        #[arg(long)]
+        max_sp_packets: u32,
+    },
+
+    /// Decode the audio records into an audio file
+    ExtractAudio {
+        #[command(flatten)]
+        input: InputArgs,
+
+        /// Audio file to write
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[command(flatten)]
+        audio: AudioArgs,
+    },
+
+    #[cfg(feature = "experimental-video")]
+    /// Decode the video records into numbered PNG files
+    ExtractFrames {
+        #[command(flatten)]
+        input: InputArgs,
+
+        /// Directory for the PNG files (or the file to write with --raw)
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Append every displayed frame to the output file as a raw big-endian framebuffer instead (colors are approximated with BT.601, not the library's conversion)
+        #[arg(long)]
+        raw: bool,
+
+        #[command(flatten)]
+        video: VideoArgs,
+    },
+
+    #[cfg(feature = "experimental-video")]
+    /// Decode a single frame into a PNG file, starting from the nearest key frame
+    ExtractFrame {
+        #[command(flatten)]
+        input: InputArgs,
+
+        /// PNG file to write
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Frame number, counted from 0
+        #[arg(long, conflicts_with = "time", required_unless_present = "time")]
+        frame: Option<u32>,
+
+        /// Time of the frame in seconds
+        #[arg(long)]
+        time: Option<f64>,
+
+        #[command(flatten)]
+        video: VideoArgs,
+    },
+
+    #[cfg(feature = "experimental-video")]
+    /// Decode both audio and video into a directory (audio.<ext> and frame_NNNNN.png)
+    Convert {
+        #[command(flatten)]
+        input: InputArgs,
+
+        /// Directory to write into
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[command(flatten)]
+        audio: AudioArgs,
+
+        #[command(flatten)]
+        video: VideoArgs,
+    },
+}
+
+#[derive(Args, Debug)]
+struct InputArgs {
+    /// Input HVQM file ("-" reads from standard input)
     input: String,
 
-    /// Print record information while processing HVQM file
+    /// Offset of the HVQM2 data inside the input file (e.g. a ROM image)
+    #[arg(long, value_parser = parse_offset, default_value = "0")]
+    offset: u64,
+}
+
+#[derive(Args, Debug)]
+struct AudioArgs {
+    /// Format of the decoded audio
+    #[arg(long, value_enum, default_value_t = OutputAudioFormat::Wav)]
+    audio_format: OutputAudioFormat,
+
+    /// Write mono audio as stereo, duplicating every sample into both channels
     #[arg(long)]
-    print_record_info: bool,
+    mono_to_stereo: bool,
 }
 
-fn main() {
-    let args = Args::parse();
+#[cfg(feature = "experimental-video")]
+#[derive(Args, Debug)]
+struct VideoArgs {
+    /// Pixel format of the decoded frames
+    #[arg(long, value_enum, default_value_t = OutputPixelFormat::Rgba5551)]
+    pixel_format: OutputPixelFormat,
+}
 
-    let input_path = &args.input;
-    let print_record_info = args.print_record_info;
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli.command, cli.json) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        },
+    }
+}
 
-    let input_file = File::open(input_path).expect("could not open input file");
-    let mut input_buf = Vec::new();
-    BufReader::new(input_file).read_to_end(&mut input_buf).expect("error");
+/*
+ * Input file or standard input. Only files can be seeked, which is
+ * all `Demuxer::at_offset` needs.
+ */
+enum Input {
+    Stdin(BufReader<std::io::Stdin>),
+    File(BufReader<File>),
+}
 
-    let hvqm_header = hvqm::HVQM2Header::new(&input_buf);
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Input::Stdin(reader) => reader.read(buf),
+            Input::File(reader) => reader.read(buf),
+        }
+    }
+}
 
-    if !hvqm_header.valid_header() {
-        panic!("invalid header");
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Input::Stdin(_) => Err(std::io::Error::new(ErrorKind::Unsupported, "standard input can't be seeked")),
+            Input::File(reader) => reader.seek(pos),
+        }
     }
+}
 
-    print!("\n");
-    print!("File version        : {}\n", hvqm_header.header_str());
-    print!("File size           : {}\n", hvqm_header.file_size);
-    print!("Image width         : {}\n", hvqm_header.width);
-    print!("Image height        : {}\n", hvqm_header.height);
-    print!("H sampling rate     : {}\n", hvqm_header.h_sampling_rate);
-    print!("V sampling rate     : {}\n", hvqm_header.v_sampling_rate);
-    print!("Compress type       : {}\n", if hvqm_header.v_sampling_rate == 1 { "4:2:2" } else { "4:1:1" });
-    print!("Y shiftnum          : {}\n", hvqm_header.y_shiftnum);
-    print!("Video quantized step: {}\n", hvqm_header.video_quantize_shift);
-    print!("Total frames        : {}\n", hvqm_header.total_frames);
-    print!("Frame interval      : {} usec\n", hvqm_header.usec_per_frame);
-    print!("Video rate          : {} frame/sec\n", 1000000.0 / hvqm_header.usec_per_frame as f32);
-    print!("Max frame size      : {} bytes\n", hvqm_header.max_frame_size);
-    print!("Max SP packets      : {} bytes\n", hvqm_header.max_sp_packets);
-    print!("Audio data format   : {}\n", hvqm_header.audio_format);
-    print!("Audio channels      : {}\n", hvqm_header.channels);
-    print!("Bits per sample     : {} bit\n", hvqm_header.sample_bits);
-    print!("Audio quantized step: {}\n", hvqm_header.audio_quantize_step);
-    print!("Total audio records : {}\n", hvqm_header.total_audio_records);
-    print!("Audio rate          : {} Hz\n", hvqm_header.samples_per_sec);
-    print!("Max audio record    : {} bytes\n", hvqm_header.max_audio_record_size);
-    print!("\n");
-    print!("Display mode        : {}\n", "16-bit RGBA");
-    print!("\n");
+fn open(args: &InputArgs) -> Result<Demuxer<Input>> {
+    let input = if args.input == "-" {
+        Input::Stdin(BufReader::new(std::io::stdin()))
+    } else {
+        Input::File(BufReader::new(File::open(&args.input)?))
+    };
 
-    let mut adpcm_state = adpcm::ADPCMstate::new();
+    if args.offset != 0 {
+        Demuxer::at_offset(input, args.offset)
+    } else {
+        Demuxer::new(input)
+    }
+}
 
-    let mut record_index = 0;
-    let mut audio_record_count = 0;
-    let mut video_record_count = 0;
+fn run(command: Command, json: bool) -> Result<ExitCode> {
+    let summary = match command {
+        Command::Info { input } => {
+            let header = HeaderReport::new(open(&input)?.header());
+            if json {
+                print_json(&InfoReport { header });
+            } else {
+                print_header(&header);
+            }
+            return Ok(ExitCode::SUCCESS);
+        },
+        Command::Records { input } => {
+            let mut demuxer = open(&input)?;
+            let header = HeaderReport::new(demuxer.header());
+            let mut summary = SummaryReport::new(demuxer.header());
+            let mut records = Vec::new();
 
-    let mut compressed_audio_size = 0;
-    let mut decoded_audio_bytes = Vec::new();
-    let mut decoded_audio_halfs = Vec::new();
+            while let Some(demuxed) = demuxer.next_record()? {
+                let report = RecordReport::new(&demuxed, input.offset).map_err(|err| err.in_record(demuxed.index, input.offset + demuxed.offset))?;
+                summary.count(&report);
+                if json {
+                    records.push(report);
+                } else {
+                    print_record(&report);
+                }
+            }
 
-    let mut offset = 0x3C;
-    loop {
-        if offset >= input_buf.len() {
-            break;
-        }
+            if json {
+                print_json(&RecordsReport { header, records, summary });
+            } else {
+                print_summary(&summary);
+            }
+            return Ok(ExitCode::SUCCESS);
+        },
+        Command::Keyframes { input } => {
+            let mut demuxer = open(&input)?;
+            let index = seek::KeyframeIndex::build(&mut demuxer)?;
+            let keyframes: Vec<KeyframeReport> = index.entries().iter().map(|entry| KeyframeReport::new(entry, input.offset)).collect();
 
-        let record = hvqm::HVQM2Record::new(&input_buf[offset..]);
+            if json {
+                print_json(&KeyframesReport { keyframes });
+            } else {
+                for keyframe in &keyframes {
+                    print_keyframe(keyframe);
+                }
+            }
+            return Ok(ExitCode::SUCCESS);
+        },
+        Command::Validate { input } => {
+            let (data, container) = read_all(&input)?;
+            let problems: Vec<ProblemReport> = validate::validate(&data, container).iter().map(|problem| ProblemReport::new(problem, input.offset)).collect();
 
-        offset += 0x8;
+            if json {
+                print_json(&ValidateReport { valid: problems.is_empty(), problems: &problems });
+            } else if problems.is_empty() {
+                println!("no problems found");
+            } else {
+                for problem in &problems {
+                    println!("0x{:X}: {}", problem.file_offset, problem.message);
+                }
+            }
 
-        let record_type = record.record_type().expect("oy noy");
-        let record_format = record.data_format().expect("nyoron");
+            return Ok(if problems.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+        },
+        Command::FixHeader { input, output, keep_padding } => {
+            let (data, _) = read_all(&InputArgs { input, offset: 0 })?;
+            let mut header = hvqm::HVQM2Header::parse(&data)?;
+            header.check_magic()?;
 
-        if print_record_info {
-            println!("record_type = {:#?}", record_type);
-            println!("format      = {:#?}", record_format);
-            println!("size        = 0x{:X} bytes", record.size);
-            println!();
-        }
+            let old = header.clone();
+            let padding = header.recompute_summary(&data)?;
+            if keep_padding {
+                header.file_size = data.len() as u32;
+            }
+
+            let mut file = BufWriter::new(File::create(&output)?);
+            file.write_all(&header.to_bytes())?;
+            file.write_all(&data[hvqm::HVQM2Header::SIZE..header.file_size as usize])?;
+            file.flush()?;
 
-        match record_type {
-            hvqm::RecordType::Audio => {
-                let audio_header = hvqm::HVQM2AudioHeader::new(&input_buf[offset..]);
+            let changes: Vec<FieldChange> = [
+                ("file_size", old.file_size, header.file_size),
+                ("total_frames", old.total_frames, header.total_frames),
+                ("max_frame_size", old.max_frame_size, header.max_frame_size),
+                ("total_audio_records", old.total_audio_records, header.total_audio_records),
+                ("max_audio_record_size", old.max_audio_record_size, header.max_audio_record_size),
+            ].into_iter().filter(|(_, old, new)| old != new).map(|(field, old, new)| FieldChange { field, old, new }).collect();
 
-                if print_record_info {
-                    println!("    samples     = {}", audio_header.samples);
+            if json {
+                print_json(&FixHeaderReport { changes, padding, padding_kept: keep_padding });
+            } else {
+                if changes.is_empty() {
+                    println!("header already up to date");
+                }
+                for change in &changes {
+                    println!("{:<21} : {} -> {}", change.field, change.old, change.new);
+                }
+                if padding > 0 {
+                    println!("{} {padding} bytes of zero padding after the last record", if keep_padding { "kept" } else { "left out" });
                 }
+            }
+            return Ok(ExitCode::SUCCESS);
+        },
+        Command::ReplaceAudio { input, wav, output } => {
+            let (data, _) = read_all(&InputArgs { input, offset: 0 })?;
+            let track = edit::AudioTrack::read_wav(BufReader::new(File::open(&wav)?))?;
+
+            let file = edit::replace_audio(&data, &track, BufWriter::new(File::create(&output)?))?;
+            file.into_inner().map_err(|err| err.into_error())?;
+
+            let header = HeaderReport::new(&hvqm::HVQM2Header::parse(&std::fs::read(&output)?)?);
+            if json {
+                print_json(&InfoReport { header });
+            } else {
+                print_header(&header);
+            }
+            return Ok(ExitCode::SUCCESS);
+        },
+        #[cfg(feature = "experimental-video")]
+        Command::Encode { input, output, fps, keyframe_interval, sampling, quantize_shift, block_error_limit, wav, bitrate, max_frame_size, max_sp_packets } => {
+            let mut source = FrameSource::open(&input)?;
+            let fps = fps.or(source.frame_rate()).unwrap_or(30.0);
+            let (h_sampling_rate, v_sampling_rate) = sampling.rates();
+
+            let Some(first) = source.next_frame()? else {
+                return Err(std::io::Error::new(ErrorKind::InvalidInput, "no frames to encode").into());
+            };
+            let header = hvqm::HVQM2Header::for_video(first.width as u16, first.height as u16, h_sampling_rate, v_sampling_rate, quantize_shift, (1_000_000.0 / fps).round() as u32, max_sp_packets);
+            let track = match wav {
+                Some(path) => Some(edit::AudioTrack::read_wav(BufReader::new(File::open(&path)?))?),
+                None => None,
+            };
 
-                let pcmbuf = adpcm_state.adpcm_decode(&input_buf[offset+4..], record_format.to_adpcm_format().expect("idk"), audio_header.samples,  false);
+            let mut movie = encode::MovieWriter::new(BufWriter::new(File::create(&output)?), &header, track.as_ref())?;
+            movie.video_mut().set_keyframe_interval(keyframe_interval);
+            movie.video_mut().set_block_error_limit(block_error_limit);
 
-                let mut pcmbuf_byte = Vec::new();
-                let mut i = 0;
-                while i < audio_header.samples {
-                    let value = pcmbuf[i as usize];
-                    let value_bytes = value.to_be_bytes();
+            let mut controller = (bitrate.is_some() || max_frame_size.is_some())
+                .then(|| rate::RateController::new(bitrate.unwrap_or(0), max_frame_size.unwrap_or(0)));
 
-                    pcmbuf_byte.extend(value_bytes);
-                    decoded_audio_bytes.extend(value_bytes);
-                    decoded_audio_halfs.push(value);
-                    i += 1;
+            let mut frame = Some(first);
+            while let Some(source_frame) = frame {
+                let picture = source_frame.to_picture(h_sampling_rate as usize, v_sampling_rate as usize);
+                match controller.as_mut() {
+                    Some(controller) => controller.write_picture(&mut movie, &picture)?,
+                    None => movie.write_picture(&picture)?,
+                };
+                frame = source.next_frame()?;
+            }
+            movie.finish()?.into_inner().map_err(|err| err.into_error())?;
+
+            let header = HeaderReport::new(&hvqm::HVQM2Header::parse(&std::fs::read(&output)?)?);
+            let rate = controller.map(|controller| RateReport::new(&controller, header.usec_per_frame));
+            if json {
+                print_json(&EncodeReport { header, rate });
+            } else {
+                print_header(&header);
+                if let Some(rate) = &rate {
+                    print_rate(rate);
                 }
+            }
+            return Ok(ExitCode::SUCCESS);
+        },
+        Command::ExtractAudio { input, output, audio } => {
+            let demuxer = open(&input)?;
+            let (audio_decoder, audio_writer) = audio_output(demuxer.header(), &audio, &output)?;
+            let mut session = Session::new();
+            session.set_audio_output(audio_decoder, audio_writer);
+            decode(session, demuxer)?
+        },
+        #[cfg(feature = "experimental-video")]
+        Command::ExtractFrames { input, output, raw, video } => {
+            let demuxer = open(&input)?;
+            let mut session = Session::new();
+            session.set_video_decoder(video_decoder(demuxer.header(), &video)?);
+            if raw {
+                session.set_framebuffer_dump(export::FramebufferDump::new(BufWriter::new(File::create(&output)?)));
+            } else {
+                session.set_png_sequence(export::PngSequence::new(&output, video.pixel_format.to_pixel_format())?);
+            }
+            decode(session, demuxer)?
+        },
+        #[cfg(feature = "experimental-video")]
+        Command::ExtractFrame { input, output, frame, time, video } => {
+            let mut player = seek::Player::new(open(&input)?)?;
+            let pixel_format = video.pixel_format.to_pixel_format();
+
+            let picture = match (frame, time) {
+                (Some(frame), _) => player.seek_to_frame(frame)?,
+                (None, time) => player.seek_to_time((time.unwrap_or(0.0).max(0.0) * 1_000_000.0) as u64)?,
+            };
+            let Some(picture) = picture else {
+                return Err(std::io::Error::new(ErrorKind::InvalidInput, "the file ends before that frame").into());
+            };
+            export::write_png(&output, picture, pixel_format)?;
+
+            let decoder = player.video_decoder();
+            let report = ExtractFrameReport { frame: decoder.frames_displayed() - 1, presentation_time: decoder.presentation_time() };
+            if json {
+                print_json(&report);
+            } else {
+                println!("frame {} at {} usec", report.frame, report.presentation_time);
+            }
+            return Ok(ExitCode::SUCCESS);
+        },
+        #[cfg(feature = "experimental-video")]
+        Command::Convert { input, output, audio, video } => {
+            let demuxer = open(&input)?;
+            std::fs::create_dir_all(&output)?;
 
-                // let output_file = File::create(format!("audio_record_{record_index:04}.pcm_raw")).expect("could not create output file");
-                // BufWriter::new(output_file).write(&pcmbuf_byte).expect("Could not write to output file");
+            let audio_path = output.join(format!("audio.{}", audio.audio_format.to_audio_file_format().extension()));
+            let (audio_decoder, audio_writer) = audio_output(demuxer.header(), &audio, &audio_path)?;
+            let mut session = Session::new();
+            session.set_audio_output(audio_decoder, audio_writer);
+            session.set_video_decoder(video_decoder(demuxer.header(), &video)?);
+            session.set_png_sequence(export::PngSequence::new(&output, video.pixel_format.to_pixel_format())?);
+            decode(session, demuxer)?
+        },
+    };
+
+    if json {
+        print_json(&DecodeReport { summary });
+    } else {
+        print_summary(&summary);
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-                compressed_audio_size += record.size;
-                audio_record_count += 1;
+/*
+ * Reads the whole input, from the HVQM2 header on. With an offset the
+ * data is assumed to be embedded in a larger image.
+ */
+fn read_all(args: &InputArgs) -> Result<(Vec<u8>, validate::Container)> {
+    let mut data = Vec::new();
+    if args.input == "-" {
+        std::io::stdin().lock().read_to_end(&mut data)?;
+    } else {
+        data = std::fs::read(&args.input)?;
+    }
+
+    if args.offset == 0 {
+        return Ok((data, validate::Container::File));
+    }
+
+    let start = (args.offset as usize).min(data.len());
+    Ok((data.split_off(start), validate::Container::Embedded))
+}
+
+/*
+ * Pictures to encode: numbered PNG files or a YUV4MPEG2 stream
+ */
+#[cfg(feature = "experimental-video")]
+enum FrameSource {
+    Png(std::vec::IntoIter<PathBuf>),
+    Y4m(import::Y4mReader<File>),
+}
+
+#[cfg(feature = "experimental-video")]
+impl FrameSource {
+    fn open(path: &Path) -> Result<FrameSource> {
+        if !path.is_dir() {
+            return Ok(FrameSource::Y4m(import::Y4mReader::new(File::open(path)?)?));
+        }
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            let file = entry?.path();
+            if file.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("png")) {
+                files.push(file);
+            }
+        }
+        files.sort();
+        Ok(FrameSource::Png(files.into_iter()))
+    }
+
+    fn frame_rate(&self) -> Option<f64> {
+        match self {
+            FrameSource::Png(_) => None,
+            FrameSource::Y4m(reader) => {
+                let (num, den) = reader.frame_rate();
+                Some(num as f64 / den as f64)
             },
-            hvqm::RecordType::Video => {
-                let mut suboffset = 0;
-
-                // TODO: handle HOLD better
-
-                if print_record_info {
-                    match record_format {
-                        hvqm::DataFormat::VideoKeyframe | hvqm::DataFormat::VideoPredict => {
-                            let video_header = hvqm::HVQM2Frame::new(&input_buf[offset..]);
-                            suboffset += 0x34;
-
-                            println!("    basisnum_offset[0] = {}", video_header.basisnum_offset[0]);
-                            println!("    basisnum_offset[1] = {}", video_header.basisnum_offset[1]);
-                            println!("    basnumrn_offset[0] = {}", video_header.basnumrn_offset[0]);
-                            println!("    basnumrn_offset[1] = {}", video_header.basnumrn_offset[1]);
-                            println!("    scale_offset[0]    = {}", video_header.scale_offset[0]);
-                            println!("    scale_offset[1]    = {}", video_header.scale_offset[1]);
-                            println!("    scale_offset[2]    = {}", video_header.scale_offset[2]);
-                            println!("    fixvl_offset[0]    = {}", video_header.fixvl_offset[0]);
-                            println!("    fixvl_offset[1]    = {}", video_header.fixvl_offset[1]);
-                            println!("    fixvl_offset[2]    = {}", video_header.fixvl_offset[2]);
-                            println!("    dcval_offset[0]    = {}", video_header.dcval_offset[0]);
-                            println!("    dcval_offset[1]    = {}", video_header.dcval_offset[1]);
-                            println!("    dcval_offset[2]    = {}", video_header.dcval_offset[2]);
-                        },
-                        hvqm::DataFormat::VideoHold => (),
-                        _  => panic!("what"),
-                    }
+        }
+    }
 
-                }
+    fn next_frame(&mut self) -> Result<Option<import::SourceFrame>> {
+        match self {
+            FrameSource::Png(files) => files.next().map(|file| import::read_png(&file)).transpose(),
+            FrameSource::Y4m(reader) => reader.next_frame(),
+        }
+    }
+}
 
-                match record_format {
-                    hvqm::DataFormat::VideoKeyframe => {
-                        let key_header = hvqm::HVQM2KeyFrame::new(&input_buf[offset+suboffset..]);
-                        suboffset += 0x10;
-
-                        if print_record_info {
-                            println!("        dcrun_offset[0] = {}", key_header.dcrun_offset[0]);
-                            println!("        dcrun_offset[1] = {}", key_header.dcrun_offset[1]);
-                            println!("        dcrun_offset[2] = {}", key_header.dcrun_offset[2]);
-                            println!("        nest_start_x    = {}", key_header.nest_start_x);
-                            println!("        nest_start_y    = {}", key_header.nest_start_y);
-                        }
-                    },
-                    hvqm::DataFormat::VideoPredict => {
-                        let predict_header = hvqm::HVQM2PredictFrame::new(&input_buf[offset+suboffset..]);
-                        suboffset += 0x8;
-
-                        if print_record_info {
-                            println!("        movevector_offset    = {}", predict_header.movevector_offset);
-                            println!("        macroblock_offset    = {}", predict_header.macroblock_offset);
-                        }
-                    },
-                    hvqm::DataFormat::VideoHold => (),
-                    _  => panic!("what"),
-                }
+/* Audio decoder and writer for `args`, writing to `path` */
+fn audio_output(hvqm_header: &hvqm::HVQM2Header, args: &AudioArgs, path: &Path) -> Result<(audio::AudioDecoder, export::AudioWriter<BufWriter<File>>)> {
+    let mut audio_decoder = audio::AudioDecoder::new(hvqm_header)?;
+    if args.mono_to_stereo {
+        audio_decoder.set_expansion(adpcm::ChannelExpansion::MonoToStereo);
+    }
+
+    let format = args.audio_format.to_audio_file_format();
+    let output = BufWriter::new(File::create(path)?);
+    let audio_writer = export::AudioWriter::new(output, format, audio_decoder.output_channels(), hvqm_header.samples_per_sec);
+    Ok((audio_decoder, audio_writer))
+}
+
+#[cfg(feature = "experimental-video")]
+fn video_decoder(hvqm_header: &hvqm::HVQM2Header, args: &VideoArgs) -> Result<video::VideoDecoder> {
+    let mut video_decoder = video::VideoDecoder::new(hvqm_header)?;
+    video_decoder.set_pixel_format(args.pixel_format.to_pixel_format());
+    Ok(video_decoder)
+}
 
-                // println!();
-                // println!("    size remaining: {}", record.size as i32 - suboffset as i32);
-                // println!();
+/* Runs every record through `session` */
+fn decode(session: Session<BufWriter<File>>, demuxer: Demuxer<Input>) -> Result<SummaryReport> {
+    let summary = SummaryReport::new(demuxer.header());
+    let stats = session.run(demuxer)?;
 
-                video_record_count += 1;
+    Ok(SummaryReport {
+        audio_record_count: stats.audio_record_count,
+        video_record_count: stats.video_record_count,
+        compressed_audio_size: stats.compressed_audio_size,
+        displayed_frame_count: stats.displayed_frame_count,
+        ..summary
+    })
+}
+
+/*
+ * JSON reports. Field names are part of the output format: add fields,
+ * don't rename or remove them.
+ */
+#[derive(Serialize)]
+struct InfoReport {
+    header: HeaderReport,
+}
+
+#[cfg(feature = "experimental-video")]
+#[derive(Serialize)]
+struct ExtractFrameReport {
+    frame: u32,
+    presentation_time: u64,    /* [usec.] */
+}
+
+#[derive(Serialize)]
+struct KeyframesReport {
+    keyframes: Vec<KeyframeReport>,
+}
+
+#[derive(Serialize)]
+struct KeyframeReport {
+    record_index: u32,
+    file_offset: u64,           /* Offset of the record header in the input file */
+    frame: u32,
+    presentation_time: u64,     /* [usec.] */
+    start_record_index: u32,    /* First record to decode, audio records before the key frame included */
+    start_file_offset: u64,
+    audio_position: u64,        /* Samples per channel before the start record */
+    adpcm_states: Vec<AdpcmStateReport>,    /* One per channel */
+}
+
+#[derive(Serialize)]
+struct AdpcmStateReport {
+    previous: i16,
+    step_index: u8,
+}
+
+impl KeyframeReport {
+    fn new(entry: &seek::KeyframeEntry, base: u64) -> KeyframeReport {
+        KeyframeReport {
+            record_index: entry.index,
+            file_offset: base + entry.offset,
+            frame: entry.frame,
+            presentation_time: entry.time,
+            start_record_index: entry.start_index,
+            start_file_offset: base + entry.start_offset,
+            audio_position: entry.audio_position,
+            adpcm_states: entry.audio_states.iter().map(|state| AdpcmStateReport { previous: state.previous(), step_index: state.step_index() }).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "experimental-video")]
+#[derive(Serialize)]
+struct EncodeReport {
+    header: HeaderReport,
+    rate: Option<RateReport>,    /* Only with a bitrate or frame size limit */
+}
+
+#[cfg(feature = "experimental-video")]
+#[derive(Serialize)]
+struct RateReport {
+    keyframes: u32,
+    predicted_frames: u32,
+    holds: u32,
+    dropped_frames: u32,
+    average_bitrate: u64,    /* [bit/sec.] */
+}
+
+#[cfg(feature = "experimental-video")]
+impl RateReport {
+    fn new(controller: &rate::RateController, usec_per_frame: u32) -> RateReport {
+        let stats = controller.stats();
+        RateReport {
+            keyframes: stats.keyframes,
+            predicted_frames: stats.predicted,
+            holds: stats.holds,
+            dropped_frames: stats.dropped,
+            average_bitrate: controller.average_bitrate(usec_per_frame),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RecordsReport {
+    header: HeaderReport,
+    records: Vec<RecordReport>,
+    summary: SummaryReport,
+}
+
+#[derive(Serialize)]
+struct DecodeReport {
+    summary: SummaryReport,
+}
+
+#[derive(Serialize)]
+struct ValidateReport<'a> {
+    valid: bool,
+    problems: &'a [ProblemReport],
+}
+
+#[derive(Serialize)]
+struct FixHeaderReport {
+    changes: Vec<FieldChange>,    /* Only the fields whose value changed */
+    padding: u64,                 /* Zero bytes after the last record */
+    padding_kept: bool,           /* Whether they were written, or left out */
+}
+
+#[derive(Serialize)]
+struct FieldChange {
+    field: &'static str,
+    old: u32,
+    new: u32,
+}
+
+#[derive(Serialize)]
+struct ProblemReport {
+    file_offset: u64,
+    kind: &'static str,
+    message: String,
+}
+
+impl ProblemReport {
+    fn new(problem: &validate::Problem, base: u64) -> ProblemReport {
+        ProblemReport {
+            file_offset: base + problem.offset,
+            kind: problem.name(),
+            message: problem.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HeaderReport {
+    file_version: String,
+    file_size: u32,
+    width: u16,
+    height: u16,
+    h_sampling_rate: u8,
+    v_sampling_rate: u8,
+    y_shiftnum: u8,
+    video_quantize_shift: u8,
+    total_frames: u32,
+    usec_per_frame: u32,
+    max_frame_size: u32,
+    max_sp_packets: u32,
+    audio_format: u8,
+    audio_format_name: Option<&'static str>,    /* null for unknown formats */
+    channels: u8,
+    sample_bits: u8,
+    audio_quantize_step: u8,
+    total_audio_records: u32,
+    samples_per_sec: u32,
+    max_audio_record_size: u32,
+}
+
+impl HeaderReport {
+    fn new(hvqm_header: &hvqm::HVQM2Header) -> HeaderReport {
+        HeaderReport {
+            file_version: hvqm_header.header_str().trim_end_matches('\0').to_string(),
+            file_size: hvqm_header.file_size,
+            width: hvqm_header.width,
+            height: hvqm_header.height,
+            h_sampling_rate: hvqm_header.h_sampling_rate,
+            v_sampling_rate: hvqm_header.v_sampling_rate,
+            y_shiftnum: hvqm_header.y_shiftnum,
+            video_quantize_shift: hvqm_header.video_quantize_shift,
+            total_frames: hvqm_header.total_frames,
+            usec_per_frame: hvqm_header.usec_per_frame,
+            max_frame_size: hvqm_header.max_frame_size,
+            max_sp_packets: hvqm_header.max_sp_packets,
+            audio_format: hvqm_header.audio_format,
+            audio_format_name: hvqm_header.audio_data_format().ok().map(|format| format.description()),
+            channels: hvqm_header.channels,
+            sample_bits: hvqm_header.sample_bits,
+            audio_quantize_step: hvqm_header.audio_quantize_step,
+            total_audio_records: hvqm_header.total_audio_records,
+            samples_per_sec: hvqm_header.samples_per_sec,
+            max_audio_record_size: hvqm_header.max_audio_record_size,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RecordReport {
+    index: u32,
+    file_offset: u64,              /* Offset of the record header in the input file */
+    record_type: &'static str,     /* "audio" or "video" */
+    format: &'static str,          /* "audio_keyframe", "audio_predict", "video_keyframe", "video_predict" or "video_hold" */
+    size: u32,                     /* Record data size, excluding the record header */
+    samples: Option<u32>,          /* Audio records only */
+    frame: Option<FrameReport>,    /* Key frames and predicted frames only */
+}
+
+impl RecordReport {
+    fn new(demuxed: &DemuxedRecord, base: u64) -> Result<RecordReport> {
+        let record = demuxed.record()?;
+
+        let (samples, frame) = match &record {
+            hvqm::Record::Audio { header, .. } => (Some(header.samples), None),
+            hvqm::Record::VideoKeyframe { frame, key_frame, .. } => (None, Some(FrameReport {
+                dcrun_offset: Some(key_frame.dcrun_offset),
+                nest_start_x: Some(key_frame.nest_start_x),
+                nest_start_y: Some(key_frame.nest_start_y),
+                ..FrameReport::new(frame)
+            })),
+            hvqm::Record::VideoPredict { frame, predict_frame, .. } => (None, Some(FrameReport {
+                movevector_offset: Some(predict_frame.movevector_offset),
+                macroblock_offset: Some(predict_frame.macroblock_offset),
+                ..FrameReport::new(frame)
+            })),
+            hvqm::Record::VideoHold => (None, None),
+        };
+
+        Ok(RecordReport {
+            index: demuxed.index,
+            file_offset: base + demuxed.offset,
+            record_type: match record.record_type() {
+                hvqm::RecordType::Audio => "audio",
+                hvqm::RecordType::Video => "video",
+            },
+            format: match record.data_format() {
+                hvqm::DataFormat::AudioKeyframe => "audio_keyframe",
+                hvqm::DataFormat::AudioPredict => "audio_predict",
+                hvqm::DataFormat::VideoKeyframe => "video_keyframe",
+                hvqm::DataFormat::VideoPredict => "video_predict",
+                hvqm::DataFormat::VideoHold => "video_hold",
             },
+            size: demuxed.header.size,
+            samples,
+            frame,
+        })
+    }
+}
+
+/* Section offsets of a video record; the key frame or predict frame ones are null otherwise */
+#[derive(Serialize)]
+struct FrameReport {
+    basisnum_offset: [u32; 2],
+    basnumrn_offset: [u32; 2],
+    scale_offset: [u32; 3],
+    fixvl_offset: [u32; 3],
+    dcval_offset: [u32; 3],
+    dcrun_offset: Option<[u32; 3]>,
+    nest_start_x: Option<u16>,
+    nest_start_y: Option<u16>,
+    movevector_offset: Option<u32>,
+    macroblock_offset: Option<u32>,
+}
+
+impl FrameReport {
+    fn new(frame: &hvqm::HVQM2Frame) -> FrameReport {
+        FrameReport {
+            basisnum_offset: frame.basisnum_offset,
+            basnumrn_offset: frame.basnumrn_offset,
+            scale_offset: frame.scale_offset,
+            fixvl_offset: frame.fixvl_offset,
+            dcval_offset: frame.dcval_offset,
+            dcrun_offset: None,
+            nest_start_x: None,
+            nest_start_y: None,
+            movevector_offset: None,
+            macroblock_offset: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SummaryReport {
+    audio_record_count: u32,
+    video_record_count: u32,
+    compressed_audio_size: u32,
+    total_frames: u32,                     /* As declared by the header */
+    displayed_frame_count: Option<u32>,    /* null when the video records were not decoded */
+}
+
+impl SummaryReport {
+    fn new(hvqm_header: &hvqm::HVQM2Header) -> SummaryReport {
+        SummaryReport {
+            audio_record_count: 0,
+            video_record_count: 0,
+            compressed_audio_size: 0,
+            total_frames: hvqm_header.total_frames,
+            displayed_frame_count: None,
         }
+    }
 
-        if print_record_info {
-            println!();
+    fn count(&mut self, record: &RecordReport) {
+        if record.samples.is_some() {
+            self.audio_record_count += 1;
+            self.compressed_audio_size += record.size;
+        } else {
+            self.video_record_count += 1;
         }
+    }
+}
+
+fn print_json(report: &impl Serialize) {
+    println!("{}", serde_json::to_string_pretty(report).expect("reports always serialize"));
+}
+
+fn print_header(header: &HeaderReport) {
+    println!("File version        : {}", header.file_version);
+    println!("File size           : {}", header.file_size);
+    println!("Image width         : {}", header.width);
+    println!("Image height        : {}", header.height);
+    println!("H sampling rate     : {}", header.h_sampling_rate);
+    println!("V sampling rate     : {}", header.v_sampling_rate);
+    println!("Compress type       : {}", if header.v_sampling_rate == 1 { "4:2:2" } else { "4:1:1" });
+    println!("Y shiftnum          : {}", header.y_shiftnum);
+    println!("Video quantized step: {}", header.video_quantize_shift);
+    println!("Total frames        : {}", header.total_frames);
+    println!("Frame interval      : {} usec", header.usec_per_frame);
+    println!("Video rate          : {} frame/sec", 1000000.0 / header.usec_per_frame as f32);
+    println!("Max frame size      : {} bytes", header.max_frame_size);
+    println!("Max SP packets      : {} bytes", header.max_sp_packets);
+    println!("Audio data format   : {} ({})", header.audio_format, header.audio_format_name.unwrap_or("unknown"));
+    println!("Audio channels      : {}", header.channels);
+    println!("Bits per sample     : {} bit", header.sample_bits);
+    println!("Audio quantized step: {}", header.audio_quantize_step);
+    println!("Total audio records : {}", header.total_audio_records);
+    println!("Audio rate          : {} Hz", header.samples_per_sec);
+    println!("Max audio record    : {} bytes", header.max_audio_record_size);
+}
+
+fn print_record(record: &RecordReport) {
+    println!("record {} at offset 0x{:X}", record.index, record.file_offset);
+    println!("record_type = {}", record.record_type);
+    println!("format      = {}", record.format);
+    println!("size        = 0x{:X} bytes", record.size);
+    println!();
 
-        offset += record.size as usize;
-        record_index += 1;
+    if let Some(samples) = record.samples {
+        println!("    samples     = {samples}");
+    }
+    if let Some(frame) = &record.frame {
+        println!("    basisnum_offset[0] = {}", frame.basisnum_offset[0]);
+        println!("    basisnum_offset[1] = {}", frame.basisnum_offset[1]);
+        println!("    basnumrn_offset[0] = {}", frame.basnumrn_offset[0]);
+        println!("    basnumrn_offset[1] = {}", frame.basnumrn_offset[1]);
+        println!("    scale_offset[0]    = {}", frame.scale_offset[0]);
+        println!("    scale_offset[1]    = {}", frame.scale_offset[1]);
+        println!("    scale_offset[2]    = {}", frame.scale_offset[2]);
+        println!("    fixvl_offset[0]    = {}", frame.fixvl_offset[0]);
+        println!("    fixvl_offset[1]    = {}", frame.fixvl_offset[1]);
+        println!("    fixvl_offset[2]    = {}", frame.fixvl_offset[2]);
+        println!("    dcval_offset[0]    = {}", frame.dcval_offset[0]);
+        println!("    dcval_offset[1]    = {}", frame.dcval_offset[1]);
+        println!("    dcval_offset[2]    = {}", frame.dcval_offset[2]);
+    }
+    if let Some(FrameReport { dcrun_offset: Some(dcrun_offset), nest_start_x: Some(nest_start_x), nest_start_y: Some(nest_start_y), .. }) = &record.frame {
+        println!("        dcrun_offset[0] = {}", dcrun_offset[0]);
+        println!("        dcrun_offset[1] = {}", dcrun_offset[1]);
+        println!("        dcrun_offset[2] = {}", dcrun_offset[2]);
+        println!("        nest_start_x    = {nest_start_x}");
+        println!("        nest_start_y    = {nest_start_y}");
+    }
+    if let Some(FrameReport { movevector_offset: Some(movevector_offset), macroblock_offset: Some(macroblock_offset), .. }) = &record.frame {
+        println!("        movevector_offset    = {movevector_offset}");
+        println!("        macroblock_offset    = {macroblock_offset}");
     }
 
-    // let output_file = File::create(format!("{input_path}.pcm_raw")).expect("could not create output file");
-    // BufWriter::new(output_file).write(&decoded_audio_bytes).expect("Could not write to output file");
+    println!();
+}
 
-    let mut out_wav_file = File::create(format!("{input_path}.wav")).expect("not");
+fn print_summary(summary: &SummaryReport) {
+    println!("compressed_audio_size = {}", summary.compressed_audio_size);
+    println!("audio_record_count    = {}", summary.audio_record_count);
+    println!("video_record_count    = {}", summary.video_record_count);
 
-    let wav_header = wav::Header::new(wav::header::WAV_FORMAT_PCM, hvqm_header.channels as u16, hvqm_header.samples_per_sec, hvqm_header.sample_bits as u16);
-    let wav_bitdepth = wav::bit_depth::BitDepth::Sixteen(decoded_audio_halfs);
-    wav::write(wav_header, &wav_bitdepth, &mut out_wav_file).expect("error when writing wav file");
+    if let Some(displayed_frames) = summary.displayed_frame_count {
+        println!("displayed_frame_count = {displayed_frames}");
 
-    println!("compressed_audio_size = {compressed_audio_size}");
-    println!("audio_record_count    = {audio_record_count}");
-    println!("video_record_count    = {video_record_count}");
+        if displayed_frames != summary.total_frames {
+            println!("warning: header says {} frames but {} were displayed", summary.total_frames, displayed_frames);
+        }
+    }
+}
+
+fn print_keyframe(keyframe: &KeyframeReport) {
+    let states: Vec<String> = keyframe.adpcm_states.iter().map(|state| format!("{}/{}", state.previous, state.step_index)).collect();
+    println!("frame {:<6} record {:<6} offset 0x{:08X}  time {:>10} usec  start record {:<6} audio sample {:<9} adpcm {}",
+        keyframe.frame, keyframe.record_index, keyframe.file_offset, keyframe.presentation_time, keyframe.start_record_index, keyframe.audio_position, states.join(" "));
+}
+
+#[cfg(feature = "experimental-video")]
+fn print_rate(rate: &RateReport) {
+    println!("Key frames          : {}", rate.keyframes);
+    println!("Predicted frames    : {}", rate.predicted_frames);
+    println!("Holds               : {} ({} dropped frames)", rate.holds, rate.dropped_frames);
+    println!("Average bitrate     : {} bit/sec", rate.average_bitrate);
+}
+
+/* Accepts decimal or 0x-prefixed hexadecimal offsets */
+fn parse_offset(arg: &str) -> std::result::Result<u64, std::num::ParseIntError> {
+    match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }
+}
+
+/* Accepts frame rates whose frame duration fits usec_per_frame (1 usec. to about 71 minutes) */
+#[cfg(feature = "experimental-video")]
+fn parse_fps(arg: &str) -> std::result::Result<f64, String> {
+    let fps: f64 = arg.parse().map_err(|err| format!("{err}"))?;
+    if !fps.is_finite() || fps <= 0.0 {
+        return Err("must be a positive number".to_string());
+    }
+
+    let usec_per_frame = (1_000_000.0 / fps).round();
+    if !(1.0..=u32::MAX as f64).contains(&usec_per_frame) {
+        return Err(format!("must be between {:.6} and 2000000", 1_000_000.0 / u32::MAX as f64));
+    }
+    Ok(fps)
 }
@@ -0,0 +1,162 @@
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::error::{Error, Result};
+use crate::hvqm::{DataFormat, HVQM2Header, HVQM2Record, RecordType};
+
+/*
+ * HVQM2Writer : Writes a file header, then one record at a time
+ *
+ * The header is written exactly as given, so writing back the header and
+ * records of a parsed file reproduces it byte for byte. The size and count
+ * fields matching what was actually written are available from
+ * `summary_header`, and `finish_with_summary` rewrites them in place when
+ * the output can seek.
+ */
+pub struct HVQM2Writer<W: Write> {
+    output: W,
+    summary: HVQM2Header,    /* Header with the summary fields of the records written so far */
+    written: u64,            /* Bytes written, including the file header */
+}
+
+impl<W: Write> HVQM2Writer<W> {
+    pub fn new(mut output: W, header: &HVQM2Header) -> Result<HVQM2Writer<W>> {
+        header.write_to(&mut output)?;
+
+        let mut summary = header.clone();
+        summary.file_size = HVQM2Header::SIZE as u32;
+        summary.total_frames = 0;
+        summary.total_audio_records = 0;
+        summary.max_frame_size = 0;
+        summary.max_audio_record_size = 0;
+
+        Ok(HVQM2Writer {
+            output,
+            summary,
+            written: HVQM2Header::SIZE as u64,
+        })
+    }
+
+    /* Writes a record of `format` whose data (excluding the record header) is `data` */
+    pub fn write_record(&mut self, format: DataFormat, data: &[u8]) -> Result<()> {
+        self.write_raw_record(&HVQM2Record::for_data(format, data.len() as u32), data)
+    }
+
+    /*
+     * Writes `header` as is, followed by `data`.
+     * `header.size` must be the length of `data`.
+     */
+    pub fn write_raw_record(&mut self, header: &HVQM2Record, data: &[u8]) -> Result<()> {
+        if header.size as usize != data.len() {
+            return Err(Error::RecordSizeMismatch { declared: header.size, actual: data.len() });
+        }
+
+        header.write_to(&mut self.output)?;
+        self.output.write_all(data)?;
+        self.written += (HVQM2Record::SIZE + data.len()) as u64;
+
+        let summary = &mut self.summary;
+        summary.file_size = self.written as u32;
+        match header.record_type() {
+            Ok(RecordType::Audio) => {
+                summary.total_audio_records += 1;
+                summary.max_audio_record_size = summary.max_audio_record_size.max(header.size);
+            },
+            Ok(RecordType::Video) => {
+                summary.total_frames += 1;
+                summary.max_frame_size = summary.max_frame_size.max(header.size);
+            },
+            Err(_) => (),
+        }
+        Ok(())
+    }
+
+    /*
+     * The header given to `new`, with file_size, total_frames,
+     * total_audio_records, max_frame_size and max_audio_record_size
+     * matching the records written so far
+     */
+    pub fn summary_header(&self) -> &HVQM2Header {
+        &self.summary
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+impl<W: Write + Seek> HVQM2Writer<W> {
+    /* Rewrites the file header with `summary_header` before finishing */
+    pub fn finish_with_summary(mut self) -> Result<W> {
+        self.output.seek(SeekFrom::Current(-(self.written as i64)))?;
+        self.summary.write_to(&mut self.output)?;
+        self.output.seek(SeekFrom::Current(self.written as i64 - HVQM2Header::SIZE as i64))?;
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::audio::AudioEncoder;
+    use crate::hvqm::{HVQM2Frame, HVQM2KeyFrame, HVQM2PredictFrame, Records};
+
+    /* Video record data: the headers, then `sections` bytes that every section offset points to the start of */
+    fn video_record(format: DataFormat, sections: usize) -> Vec<u8> {
+        let sub_header_size = match format {
+            DataFormat::VideoKeyframe => HVQM2KeyFrame::SIZE,
+            _ => HVQM2PredictFrame::SIZE,
+        };
+        let start = (HVQM2Frame::SIZE + sub_header_size) as u32;
+
+        let frame = HVQM2Frame {
+            basisnum_offset: [start; 2],
+            basnumrn_offset: [start; 2],
+            scale_offset: [start; 3],
+            fixvl_offset: [start; 3],
+            dcval_offset: [start; 3],
+        };
+        let mut data = frame.to_bytes().to_vec();
+        match format {
+            DataFormat::VideoKeyframe => data.extend(HVQM2KeyFrame { dcrun_offset: [start; 3], nest_start_x: 0, nest_start_y: 0 }.to_bytes()),
+            _ => data.extend(HVQM2PredictFrame { movevector_offset: start, macroblock_offset: start }.to_bytes()),
+        }
+        data.extend((0..sections).map(|i| (i * 7) as u8));
+        data
+    }
+
+    #[test]
+    fn raw_records_rewrite_byte_for_byte() {
+        let mut header = HVQM2Header::for_video(32, 16, 2, 2, 4, 100_000, 1);
+        header.samples_per_sec = 8000;
+        let samples: Vec<i16> = (0..800).map(|i| ((i * 37 % 2000) as i16 - 1000) * 16).collect();
+
+        let mut encoder = AudioEncoder::new(&header).unwrap();
+        let mut movie = HVQM2Writer::new(Cursor::new(Vec::new()), &header).unwrap();
+        movie.write_record(DataFormat::AudioKeyframe, &encoder.encode_record(DataFormat::AudioKeyframe, &samples[..400]).unwrap()).unwrap();
+        movie.write_record(DataFormat::VideoKeyframe, &video_record(DataFormat::VideoKeyframe, 40)).unwrap();
+        movie.write_record(DataFormat::AudioPredict, &encoder.encode_record(DataFormat::AudioPredict, &samples[400..]).unwrap()).unwrap();
+        movie.write_record(DataFormat::VideoPredict, &video_record(DataFormat::VideoPredict, 12)).unwrap();
+        movie.write_record(DataFormat::VideoHold, &[]).unwrap();
+        let mut file = movie.finish_with_summary().unwrap().into_inner();
+
+        /* The header is written as given, stale summary fields included */
+        file[0x1C..0x20].copy_from_slice(&1234u32.to_be_bytes());
+
+        let parsed = HVQM2Header::parse(&file).unwrap();
+        let mut writer = HVQM2Writer::new(Vec::new(), &parsed).unwrap();
+        let mut formats = Vec::new();
+        for entry in Records::new(&file) {
+            let entry = entry.unwrap();
+            formats.push(entry.record.data_format());
+            writer.write_raw_record(&entry.header, entry.data).unwrap();
+        }
+        assert_eq!(formats, [DataFormat::AudioKeyframe, DataFormat::VideoKeyframe, DataFormat::AudioPredict, DataFormat::VideoPredict, DataFormat::VideoHold]);
+
+        assert_eq!(writer.summary_header().total_frames, 3);
+        assert_eq!(writer.summary_header().file_size as usize, file.len());
+        assert_eq!(writer.finish().unwrap(), file);
+    }
+}
@@ -0,0 +1,338 @@
+use std::io::Write;
+
+use crate::encode::{EncodedFrame, MovieWriter, VideoEncoder};
+use crate::error::{Error, Result};
+use crate::hvqm::{DataFormat, HVQM2Record};
+use crate::video::Picture;
+
+const MAX_BLOCK_ERROR_LIMIT: u32 = 1 << 20;    /* Past this every block is coded with its DC only */
+
+/*
+ * RateStats : What the rate controller did so far
+ */
+#[derive(Copy, Clone, Default, Debug)]
+pub struct RateStats {
+    pub keyframes: u32,
+    pub predicted: u32,
+    pub holds: u32,           /* All holds, `dropped` included */
+    pub dropped: u32,         /* Frames replaced by a hold because they didn't fit the budget */
+    pub bytes: u64,           /* Size of all records written, record headers included */
+}
+
+/*
+ * RateController : Encodes pictures within a bitrate and a frame size limit
+ *
+ * `bitrate` is the average rate of the whole stream (audio and video
+ * records with their headers) in bits per second. Unused budget is carried
+ * over to later frames, up to one second worth of data. The audio record
+ * written with a frame is only known once it is written, so it is paid for
+ * by the following frames.
+ *
+ * `max_frame_size` is a hard limit on the size of every video record (the
+ * `max_frame_size` header field). A zero disables either limit.
+ *
+ * The quality of every frame is set through the encoder's block error
+ * limit, never going under the limit the encoder started with: it is raised
+ * until the frame fits, and lowered back over the next frames while they
+ * come out well under budget. A predicted frame that doesn't fit even at
+ * the lowest quality is dropped and replaced by a hold, and so is a key
+ * frame, which is then tried again on the next picture. A predicted frame
+ * bigger than the last key frame (usually a scene change) is coded as a
+ * key frame instead if that is smaller. The first key frame only has to
+ * fit `max_frame_size`.
+ *
+ * SP FIFO packets are out of scope: frames are neither limited nor
+ * measured by the packets the player needs for them, because what it
+ * counts as one is unknown. `max_sp_packets` keeps the value given to
+ * `HVQM2Header::for_video`, and frames that need more packets than that
+ * are not detected.
+ */
+pub struct RateController {
+    bitrate: u32,
+    max_frame_size: u32,
+    min_error_limit: u32,     /* Best quality allowed, the encoder's initial limit */
+    error_limit: u32,         /* Current quality */
+    last_keyframe_size: usize,
+    balance: i64,             /* Budget carried over [bytes] */
+    stats: RateStats,
+}
+
+impl RateController {
+    /* `bitrate` in bits per second and `max_frame_size` in bytes, 0 for no limit */
+    pub fn new(bitrate: u32, max_frame_size: u32) -> RateController {
+        RateController {
+            bitrate,
+            max_frame_size,
+            min_error_limit: 0,
+            error_limit: 0,
+            last_keyframe_size: usize::MAX,
+            balance: 0,
+            stats: RateStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> RateStats {
+        self.stats
+    }
+
+    /* Average bitrate of the records written so far, for frames `usec_per_frame` long */
+    pub fn average_bitrate(&self, usec_per_frame: u32) -> u64 {
+        let frames = (self.stats.keyframes + self.stats.predicted + self.stats.holds) as u64;
+        match frames * usec_per_frame as u64 {
+            0 => 0,
+            usec => self.stats.bytes * 8 * 1_000_000 / usec,
+        }
+    }
+
+    /* Encodes and writes the next picture, returns the video record format used */
+    pub fn write_picture<W: Write>(&mut self, movie: &mut MovieWriter<W>, picture: &Picture) -> Result<DataFormat> {
+        if self.stats.keyframes == 0 {
+            self.min_error_limit = movie.video().block_error_limit();
+            self.error_limit = self.min_error_limit;
+        }
+
+        let usec_per_frame = movie.summary_header().usec_per_frame as i64;
+        let frame_bytes = self.bitrate as i64 * usec_per_frame / 8_000_000;
+        self.balance += frame_bytes;
+
+        /* The first key frame is always coded, later frames pay for it */
+        let mut budget = match self.bitrate {
+            0 => usize::MAX,
+            _ if self.stats.keyframes == 0 => usize::MAX,
+            _ => self.balance.max(0) as usize,
+        };
+        budget = budget.saturating_sub(HVQM2Record::SIZE);
+        if self.max_frame_size != 0 {
+            budget = budget.min(self.max_frame_size as usize);
+        }
+
+        let (encoder, frame) = match movie.video().next_format(picture) {
+            DataFormat::VideoHold => (None, movie.video_mut().hold()?),
+            format => match self.fit(movie.video(), picture, format, budget)? {
+                Some((encoder, frame)) => (Some(encoder), frame),
+                None => {
+                    self.stats.dropped += 1;
+                    (None, movie.video_mut().hold()?)
+                },
+            },
+        };
+        if let Some(encoder) = encoder {
+            *movie.video_mut() = encoder;
+        }
+
+        let before = movie.summary_header().file_size as u64;
+        movie.write_frame(&frame)?;
+        let written = movie.summary_header().file_size as u64 - before;
+
+        self.stats.bytes += written;
+        match frame.format {
+            DataFormat::VideoKeyframe => {
+                self.stats.keyframes += 1;
+                self.last_keyframe_size = frame.data.len();
+            },
+            DataFormat::VideoPredict => self.stats.predicted += 1,
+            _ => self.stats.holds += 1,
+        }
+
+        self.balance -= written as i64;
+        if self.bitrate != 0 {
+            self.balance = self.balance.min(self.bitrate as i64 / 8);
+        }
+
+        /* Win back quality while frames come out well under budget */
+        if frame.format != DataFormat::VideoHold && frame.data.len() < budget / 2 {
+            self.error_limit = (self.error_limit * 3 / 4).max(self.min_error_limit);
+        }
+
+        Ok(frame.format)
+    }
+
+    /*
+     * Codes `picture` as `format` on a copy of `encoder`, lowering the
+     * quality until it fits in `budget` bytes. Returns the updated encoder
+     * and the frame, or `None` if a predicted frame can't fit.
+     */
+    fn fit(&mut self, encoder: &VideoEncoder, picture: &Picture, format: DataFormat, budget: usize) -> Result<Option<(VideoEncoder, EncodedFrame)>> {
+        loop {
+            let mut trial = encoder.clone();
+            trial.set_block_error_limit(self.error_limit);
+
+            let mut result = match format {
+                DataFormat::VideoKeyframe => trial.encode_keyframe(picture)?,
+                _ => trial.encode_predict(picture)?,
+            };
+
+            if format == DataFormat::VideoPredict && result.data.len() > self.last_keyframe_size {
+                let mut key = encoder.clone();
+                key.set_block_error_limit(self.error_limit);
+                let key_frame = key.encode_keyframe(picture)?;
+                if key_frame.data.len() < result.data.len() {
+                    (trial, result) = (key, key_frame);
+                }
+            }
+
+            if result.data.len() <= budget {
+                return Ok(Some((trial, result)));
+            }
+
+            if self.error_limit >= MAX_BLOCK_ERROR_LIMIT {
+                /* Nothing can be shown without a first key frame */
+                return match self.stats.keyframes {
+                    0 => Err(Error::FrameTooLarge { size: result.data.len(), limit: self.max_frame_size }),
+                    _ => Ok(None),
+                };
+            }
+            self.error_limit = (self.error_limit.max(1) * 2).min(MAX_BLOCK_ERROR_LIMIT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::hvqm::{HVQM2Header, RecordType, Records};
+
+    const USEC_PER_FRAME: u32 = 33333;
+
+    fn header() -> HVQM2Header {
+        HVQM2Header::for_video(32, 32, 2, 2, 4, USEC_PER_FRAME, 7)
+    }
+
+    fn new_movie() -> MovieWriter<Cursor<Vec<u8>>> {
+        MovieWriter::new(Cursor::new(Vec::new()), &header(), None).unwrap()
+    }
+
+    /* Pseudo-random pixels, nothing in common between two seeds */
+    fn noise(seed: u32) -> Picture {
+        let mut picture = VideoEncoder::new(&header()).unwrap().new_picture();
+        let mut state = seed.wrapping_mul(2654435761).wrapping_add(12345);
+        for plane in [&mut picture.y, &mut picture.u, &mut picture.v] {
+            for pixel in plane.pixels.iter_mut() {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                *pixel = (state >> 16) as u8;
+            }
+        }
+        picture
+    }
+
+    /* Gradient moving by `frame`, cheap to code */
+    fn smooth(frame: usize) -> Picture {
+        let mut picture = VideoEncoder::new(&header()).unwrap().new_picture();
+        for plane in [&mut picture.y, &mut picture.u, &mut picture.v] {
+            for y in 0..plane.height {
+                for x in 0..plane.width {
+                    plane.set(x, y, (40 + x * 2 + y + frame * 3) as u8);
+                }
+            }
+        }
+        picture
+    }
+
+    /* Size of the video record written for `picture`, record header included */
+    fn write(controller: &mut RateController, movie: &mut MovieWriter<Cursor<Vec<u8>>>, picture: &Picture) -> (DataFormat, u32) {
+        let before = movie.summary_header().file_size;
+        let format = controller.write_picture(movie, picture).unwrap();
+        (format, movie.summary_header().file_size - before)
+    }
+
+    #[test]
+    fn max_frame_size_is_respected() {
+        let mut movie = new_movie();
+        let mut controller = RateController::new(0, 600);
+        for seed in 0..6 {
+            controller.write_picture(&mut movie, &noise(seed)).unwrap();
+        }
+        let file = movie.finish().unwrap().into_inner();
+
+        let header = HVQM2Header::parse(&file).unwrap();
+        assert!(header.max_frame_size <= 600);
+        assert_eq!(header.max_sp_packets, 7);    /* Not computed, kept as given */
+        for entry in Records::new(&file) {
+            let entry = entry.unwrap();
+            if entry.header.record_type().unwrap() == RecordType::Video {
+                assert!(entry.header.size <= 600, "record {}: {} bytes", entry.index, entry.header.size);
+            }
+        }
+        assert_eq!(controller.stats().dropped, 0);
+    }
+
+    #[test]
+    fn bitrate_is_roughly_hit() {
+        let mut movie = new_movie();
+        let mut unlimited = new_movie();
+        let mut controller = RateController::new(100_000, 0);
+        let mut reference = RateController::new(0, 0);
+        for seed in 0..60 {
+            controller.write_picture(&mut movie, &noise(seed)).unwrap();
+            reference.write_picture(&mut unlimited, &noise(seed)).unwrap();
+        }
+
+        /* Far over the target without control, within 10% of it with */
+        assert!(reference.average_bitrate(USEC_PER_FRAME) > 250_000);
+        let bitrate = controller.average_bitrate(USEC_PER_FRAME);
+        assert!((90_000..=110_000).contains(&bitrate), "{bitrate} bit/sec");
+        assert_eq!(controller.stats().bytes, movie.summary_header().file_size as u64 - HVQM2Header::SIZE as u64);
+    }
+
+    #[test]
+    fn unused_budget_is_carried_over_up_to_one_second() {
+        let bitrate = 8_000;
+        let frame_bytes = bitrate * USEC_PER_FRAME / 8_000_000;
+
+        /* After 300 holds an uncapped budget would fit the frame at full quality */
+        let full_size = VideoEncoder::new(&header()).unwrap().encode_keyframe(&noise(1)).unwrap().data.len() as u32;
+        assert!(full_size > bitrate / 8);
+        assert!(full_size < 300 * frame_bytes / 2);
+
+        for holds in [20, 300] {
+            let mut movie = new_movie();
+            movie.video_mut().set_keyframe_interval(0);
+            let mut controller = RateController::new(bitrate, 0);
+            write(&mut controller, &mut movie, &smooth(0));
+            for _ in 0..holds {
+                assert_eq!(write(&mut controller, &mut movie, &smooth(0)).0, DataFormat::VideoHold);
+            }
+
+            /* Far more than one frame's budget, never more than one second's */
+            let (format, size) = write(&mut controller, &mut movie, &noise(1));
+            assert_ne!(format, DataFormat::VideoHold);
+            assert!(size > frame_bytes * 2, "{holds} holds: {size} bytes");
+            assert!(size <= bitrate / 8, "{holds} holds: {size} bytes");
+        }
+    }
+
+    #[test]
+    fn frames_over_budget_are_dropped_to_holds() {
+        let mut movie = new_movie();
+        let mut controller = RateController::new(16_000, 0);
+        let formats: Vec<_> = (0..4).map(|seed| controller.write_picture(&mut movie, &noise(seed)).unwrap()).collect();
+
+        /* The first key frame spends the budget of the next frames */
+        assert_eq!(formats, [DataFormat::VideoKeyframe, DataFormat::VideoHold, DataFormat::VideoHold, DataFormat::VideoHold]);
+        let stats = controller.stats();
+        assert_eq!((stats.holds, stats.dropped), (3, 3));
+    }
+
+    #[test]
+    fn scene_change_is_coded_as_a_key_frame() {
+        let mut movie = new_movie();
+        movie.video_mut().set_keyframe_interval(0);
+        let mut controller = RateController::new(0, 0);
+        let formats: Vec<_> = [smooth(0), smooth(1), noise(1)].iter().map(|picture| controller.write_picture(&mut movie, picture).unwrap()).collect();
+
+        assert_eq!(formats, [DataFormat::VideoKeyframe, DataFormat::VideoPredict, DataFormat::VideoKeyframe]);
+        assert_eq!(controller.stats().keyframes, 2);
+    }
+
+    #[test]
+    fn first_key_frame_must_fit_the_frame_size_limit() {
+        let mut movie = new_movie();
+        let mut controller = RateController::new(0, 10);
+        match controller.write_picture(&mut movie, &noise(0)) {
+            Err(Error::FrameTooLarge { limit: 10, size }) => assert!(size > 10),
+            _ => panic!("oversized first key frame accepted"),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::io::{Read, Seek};
+
+use crate::adpcm::ADPCMstate;
+use crate::audio::AudioDecoder;
+use crate::demux::Demuxer;
+use crate::error::{Error, Result};
+use crate::hvqm::Record;
+#[cfg(feature = "experimental-video")]
+use crate::{hvqm::HVQM2Header, video::{Picture, VideoDecoder}};
+
+/*
+ * KeyframeEntry : Where decoding can start again
+ *
+ * Audio records directly before a key frame usually go with it, so
+ * decoding starts at the first of them (`start_index`, `start_offset`),
+ * or at the key frame itself if it follows another video record. The audio
+ * then resumes `audio_position` samples into the track, from
+ * `audio_states`.
+ */
+#[derive(Clone, Debug)]
+pub struct KeyframeEntry {
+    pub index: u32,                      /* Record index of the key frame */
+    pub offset: u64,                     /* Offset of its record header from the start of the file header */
+    pub frame: u32,                      /* Frame number (video records before it, holds included) */
+    pub time: u64,                       /* Presentation time [usec.] */
+    pub start_index: u32,                /* Record index to start decoding at */
+    pub start_offset: u64,               /* Offset of that record */
+    pub audio_position: u64,             /* Samples per channel in the audio records before the start */
+    pub audio_states: Vec<ADPCMstate>,   /* ADPCM state of every channel at the start (empty without audio) */
+}
+
+/*
+ * KeyframeIndex : Every key frame of a file, in file order
+ */
+pub struct KeyframeIndex {
+    entries: Vec<KeyframeEntry>,
+    usec_per_frame: u32,
+}
+
+impl KeyframeIndex {
+    /*
+     * Reads every record of `demuxer` from the first one, decoding the
+     * audio to know the ADPCM state at each key frame, then rewinds it.
+     * The audio is left out if the header's audio format can't be decoded.
+     */
+    pub fn build<R: Read + Seek>(demuxer: &mut Demuxer<R>) -> Result<KeyframeIndex> {
+        let header = demuxer.header().clone();
+        let mut audio_decoder = AudioDecoder::new(&header).ok();
+        let mut entries = Vec::new();
+        let mut frame = 0;
+        let mut audio_position = 0;
+        let mut start = None;    /* First audio record since the last video record, with the audio state there */
+
+        demuxer.rewind()?;
+        while let Some(demuxed) = demuxer.next_record()? {
+            let in_record = |err: Error| err.in_record(demuxed.index, demuxer.base() + demuxed.offset);
+            let audio_states = |decoder: &Option<AudioDecoder>| decoder.as_ref().map(|decoder| decoder.states().to_vec()).unwrap_or_default();
+
+            match demuxed.record().map_err(in_record)? {
+                Record::Audio { format, header: audio_header, data } => {
+                    if start.is_none() {
+                        start = Some((demuxed.index, demuxed.offset, audio_position, audio_states(&audio_decoder)));
+                    }
+                    if let Some(decoder) = audio_decoder.as_mut() {
+                        decoder.decode(data, format, audio_header.samples).map_err(in_record)?;
+                    }
+                    audio_position += audio_header.samples as u64;
+                },
+                record => {
+                    let (start_index, start_offset, start_position, start_states) = start
+                        .take()
+                        .unwrap_or_else(|| (demuxed.index, demuxed.offset, audio_position, audio_states(&audio_decoder)));
+
+                    if let Record::VideoKeyframe { .. } = record {
+                        entries.push(KeyframeEntry {
+                            index: demuxed.index,
+                            offset: demuxed.offset,
+                            frame,
+                            time: frame as u64 * header.usec_per_frame as u64,
+                            start_index,
+                            start_offset,
+                            audio_position: start_position,
+                            audio_states: start_states,
+                        });
+                    }
+                    frame += 1;
+                },
+            }
+        }
+        demuxer.rewind()?;
+
+        Ok(KeyframeIndex {
+            entries,
+            usec_per_frame: header.usec_per_frame,
+        })
+    }
+
+    pub fn entries(&self) -> &[KeyframeEntry] {
+        &self.entries
+    }
+
+    /* Last key frame at or before `frame` */
+    pub fn entry_for_frame(&self, frame: u32) -> Option<&KeyframeEntry> {
+        self.entries.iter().take_while(|entry| entry.frame <= frame).last()
+    }
+
+    /* Last key frame at or before the frame shown at `time` [usec.] */
+    pub fn entry_for_time(&self, time: u64) -> Option<&KeyframeEntry> {
+        self.entry_for_frame(self.frame_at(time))
+    }
+
+    /* Frame shown at `time` [usec.] */
+    pub fn frame_at(&self, time: u64) -> u32 {
+        match self.usec_per_frame {
+            0 => 0,
+            usec_per_frame => (time / usec_per_frame as u64).min(u32::MAX as u64) as u32,
+        }
+    }
+}
+
+/*
+ * Player : Decodes a file from any frame
+ *
+ * Seeking jumps to the nearest key frame at or before the requested frame
+ * (to the audio records just before it) and decodes forward from there.
+ * Audio decoded along the way is kept from the requested frame on and can
+ * be collected with `take_audio`.
+ */
+#[cfg(feature = "experimental-video")]
+pub struct Player<R: Read + Seek> {
+    demuxer: Demuxer<R>,
+    index: KeyframeIndex,
+    video_decoder: VideoDecoder,
+    audio_decoder: Option<AudioDecoder>,
+    audio: Vec<i16>,           /* Interleaved samples not taken yet */
+    audio_position: u64,       /* Samples per channel decoded, up to the end of `audio` */
+}
+
+#[cfg(feature = "experimental-video")]
+impl<R: Read + Seek> Player<R> {
+    /*
+     * Builds the key frame index of `demuxer`, then starts at the first
+     * record. A demuxer that doesn't start at position 0 of its stream must
+     * come from `Demuxer::at_offset` or `Demuxer::at_current_position`.
+     */
+    pub fn new(mut demuxer: Demuxer<R>) -> Result<Player<R>> {
+        let index = KeyframeIndex::build(&mut demuxer)?;
+        let header = demuxer.header();
+
+        Ok(Player {
+            video_decoder: VideoDecoder::new(header)?,
+            audio_decoder: AudioDecoder::new(header).ok(),
+            demuxer,
+            index,
+            audio: Vec::new(),
+            audio_position: 0,
+        })
+    }
+
+    pub fn header(&self) -> &HVQM2Header {
+        self.demuxer.header()
+    }
+
+    pub fn index(&self) -> &KeyframeIndex {
+        &self.index
+    }
+
+    pub fn video_decoder(&self) -> &VideoDecoder {
+        &self.video_decoder
+    }
+
+    pub fn video_decoder_mut(&mut self) -> &mut VideoDecoder {
+        &mut self.video_decoder
+    }
+
+    /* Samples per channel decoded so far, `take_audio` included */
+    pub fn audio_position(&self) -> u64 {
+        self.audio_position
+    }
+
+    /* Interleaved audio decoded since the last call (or the last seek) */
+    pub fn take_audio(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.audio)
+    }
+
+    /*
+     * Decodes records up to the next video record and returns the picture
+     * it displays, or `None` at the end of the file
+     */
+    pub fn next_picture(&mut self) -> Result<Option<&Picture>> {
+        while let Some(demuxed) = self.demuxer.next_record()? {
+            let base = self.demuxer.base();
+            let in_record = |err: Error| err.in_record(demuxed.index, base + demuxed.offset);
+
+            match demuxed.record().map_err(in_record)? {
+                Record::Audio { format, header, data } => {
+                    if let Some(decoder) = self.audio_decoder.as_mut() {
+                        self.audio.extend(decoder.decode(data, format, header.samples).map_err(in_record)?);
+                    }
+                    self.audio_position += header.samples as u64;
+                },
+                record => {
+                    self.video_decoder.decode(record.data_format(), record.video_payload()).map_err(in_record)?;
+                    return Ok(Some(self.video_decoder.picture()));
+                },
+            }
+        }
+
+        Ok(None)
+    }
+
+    /*
+     * Shows frame `frame` (counted from 0, holds included), decoding from
+     * the key frame at or before it. Returns `None` if the file has fewer
+     * frames.
+     */
+    pub fn seek_to_frame(&mut self, frame: u32) -> Result<Option<&Picture>> {
+        let entry = self.index.entry_for_frame(frame).ok_or(Error::MissingKeyframe)?;
+
+        self.demuxer.seek_to_record(entry.start_offset, entry.start_index)?;
+        self.video_decoder.set_frames_displayed(entry.frame);
+        if let Some(decoder) = self.audio_decoder.as_mut() {
+            decoder.set_states(&entry.audio_states);
+        }
+        self.audio_position = entry.audio_position;
+
+        /* Audio belonging to the frames skipped over is dropped */
+        loop {
+            self.audio.clear();
+            if self.next_picture()?.is_none() {
+                return Ok(None);
+            }
+            if self.video_decoder.frames_displayed() > frame {
+                return Ok(Some(self.video_decoder.picture()));
+            }
+        }
+    }
+
+    /* Shows the frame displayed at `time` [usec.], see `seek_to_frame` */
+    pub fn seek_to_time(&mut self, time: u64) -> Result<Option<&Picture>> {
+        self.seek_to_frame(self.index.frame_at(time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::audio::AudioEncoder;
+    use crate::hvqm::{DataFormat, HVQM2AudioHeader, HVQM2Header};
+    use crate::mux::HVQM2Writer;
+
+    const BASE: usize = 100;    /* Offset of the embedded files in their image */
+
+    /* `file` at `BASE` of a larger image */
+    fn embed(file: &[u8]) -> Vec<u8> {
+        let mut image = vec![0xAA; BASE];
+        image.extend(file);
+        image.extend([0xBB; 16]);
+        image
+    }
+
+    #[test]
+    fn errors_give_the_offset_in_the_stream() {
+        let mut header = HVQM2Header::for_video(16, 16, 2, 2, 4, 33333, 1);
+        header.samples_per_sec = 8000;
+        let mut encoder = AudioEncoder::new(&header).unwrap();
+        let mut writer = HVQM2Writer::new(Cursor::new(Vec::new()), &header).unwrap();
+        writer.write_record(DataFormat::AudioKeyframe, &encoder.encode_record(DataFormat::AudioKeyframe, &[100; 50]).unwrap()).unwrap();
+        writer.write_record(DataFormat::VideoHold, &[]).unwrap();
+        let offset = writer.summary_header().file_size as usize;
+        let mut data = encoder.encode_record(DataFormat::AudioKeyframe, &[200; 50]).unwrap();
+        data[HVQM2AudioHeader::SIZE + 1] = 0x7F;    /* Step index */
+        writer.write_record(DataFormat::AudioKeyframe, &data).unwrap();
+        let image = embed(&writer.finish_with_summary().unwrap().into_inner());
+
+        let mut demuxer = Demuxer::at_offset(Cursor::new(&image), BASE as u64).unwrap();
+        match KeyframeIndex::build(&mut demuxer) {
+            Err(Error::InRecord { index: 2, offset: error_offset, source }) => {
+                assert_eq!(error_offset, (BASE + offset) as u64);
+                assert!(matches!(*source, Error::InvalidStepIndex { value: 0x7F }));
+            },
+            _ => panic!("invalid step index accepted"),
+        }
+    }
+
+    #[cfg(feature = "experimental-video")]
+    mod player {
+        use super::*;
+        use crate::edit::AudioTrack;
+        use crate::encode::MovieWriter;
+
+        /* Frames 5 and 6 are the same picture, so frame 6 is a hold */
+        fn picture(movie: &MovieWriter<Cursor<Vec<u8>>>, frame: usize) -> Picture {
+            let frame = if frame == 6 { 5 } else { frame };
+            let mut picture = movie.video().new_picture();
+            for plane in [&mut picture.y, &mut picture.u, &mut picture.v] {
+                for y in 0..plane.height {
+                    for x in 0..plane.width {
+                        plane.set(x, y, (30 + x * 3 + y * 2 + frame * 5) as u8);
+                    }
+                }
+            }
+            picture
+        }
+
+        /* 10 frames with a key frame every 4, and a second of audio */
+        fn movie() -> Vec<u8> {
+            let header = HVQM2Header::for_video(32, 16, 2, 2, 4, 100_000, 1);
+            let track = AudioTrack {
+                channels: 1,
+                sample_rate: 8000,
+                samples: (0..8000).map(|i| ((i * 53 % 1000) as i16 - 500) * 20).collect(),
+            };
+
+            let mut movie = MovieWriter::new(Cursor::new(Vec::new()), &header, Some(&track)).unwrap();
+            movie.video_mut().set_keyframe_interval(4);
+            for frame in 0..10 {
+                let picture = picture(&movie, frame);
+                movie.write_picture(&picture).unwrap();
+            }
+            movie.finish().unwrap().into_inner()
+        }
+
+        /* Luma, audio since the previous frame and audio position of every frame */
+        type Frame = (Vec<u8>, Vec<i16>, u64);
+
+        fn frame(player: &mut Player<Cursor<&Vec<u8>>>, picture: &Picture) -> Frame {
+            (picture.y.pixels.clone(), player.take_audio(), player.audio_position())
+        }
+
+        #[test]
+        fn seeking_matches_a_linear_decode() {
+            let image = embed(&movie());
+            let open = || Player::new(Demuxer::at_offset(Cursor::new(&image), BASE as u64).unwrap()).unwrap();
+
+            let mut player = open();
+            let mut linear: Vec<Frame> = Vec::new();
+            while let Some(picture) = player.next_picture().unwrap() {
+                let picture = picture.clone();
+                linear.push(frame(&mut player, &picture));
+            }
+            assert_eq!(linear.len(), 10);
+            assert!(linear.iter().all(|(_, audio, _)| !audio.is_empty()));
+            assert_eq!(player.index().entries().iter().map(|entry| entry.frame).collect::<Vec<_>>(), [0, 4, 8]);
+
+            /* Backwards, so every seek goes back in the file */
+            let mut player = open();
+            for n in (0..10).rev() {
+                let picture = player.seek_to_frame(n).unwrap().unwrap().clone();
+                assert!(frame(&mut player, &picture) == linear[n as usize], "frame {n}");
+            }
+            assert!(player.seek_to_frame(10).unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::io::{Read, Write};
+
+use crate::audio::AudioDecoder;
+use crate::demux::{DemuxedRecord, Demuxer};
+use crate::error::Result;
+#[cfg(feature = "experimental-video")]
+use crate::export::{FramebufferDump, PngSequence};
+use crate::export::AudioWriter;
+use crate::hvqm::Record;
+#[cfg(feature = "experimental-video")]
+use crate::video::VideoDecoder;
+
+/*
+ * SessionStats : What a session went through
+ */
+#[derive(Clone, Debug, Default)]
+pub struct SessionStats {
+    pub audio_record_count: u32,
+    pub video_record_count: u32,
+    pub compressed_audio_size: u32,        /* Audio record data, excluding the record headers [byte] */
+    pub displayed_frame_count: Option<u32>,    /* `None` when the video records were not decoded */
+}
+
+/*
+ * Session : Decodes every record of a file into the outputs that are set
+ *
+ * Audio or video records are only decoded when their decoder is set, the
+ * others are only counted.
+ */
+pub struct Session<W: Write> {
+    stats: SessionStats,
+    audio_decoder: Option<AudioDecoder>,
+    audio_writer: Option<AudioWriter<W>>,
+    #[cfg(feature = "experimental-video")]
+    video_decoder: Option<VideoDecoder>,
+    #[cfg(feature = "experimental-video")]
+    png_sequence: Option<PngSequence>,
+    #[cfg(feature = "experimental-video")]
+    framebuffer_dump: Option<FramebufferDump<W>>,
+}
+
+impl<W: Write> Session<W> {
+    pub fn new() -> Session<W> {
+        Session {
+            stats: SessionStats::default(),
+            audio_decoder: None,
+            audio_writer: None,
+            #[cfg(feature = "experimental-video")]
+            video_decoder: None,
+            #[cfg(feature = "experimental-video")]
+            png_sequence: None,
+            #[cfg(feature = "experimental-video")]
+            framebuffer_dump: None,
+        }
+    }
+
+    /* Decodes the audio records with `decoder` into `writer` */
+    pub fn set_audio_output(&mut self, decoder: AudioDecoder, writer: AudioWriter<W>) {
+        self.audio_decoder = Some(decoder);
+        self.audio_writer = Some(writer);
+    }
+
+    /* Decodes the video records with `decoder`, see `set_png_sequence` and `set_framebuffer_dump` for the output */
+    #[cfg(feature = "experimental-video")]
+    pub fn set_video_decoder(&mut self, decoder: VideoDecoder) {
+        self.video_decoder = Some(decoder);
+    }
+
+    /* Writes every displayed picture to `png_sequence`, with a video decoder set */
+    #[cfg(feature = "experimental-video")]
+    pub fn set_png_sequence(&mut self, png_sequence: PngSequence) {
+        self.png_sequence = Some(png_sequence);
+    }
+
+    /* Writes every displayed framebuffer to `framebuffer_dump`, with a video decoder set */
+    #[cfg(feature = "experimental-video")]
+    pub fn set_framebuffer_dump(&mut self, framebuffer_dump: FramebufferDump<W>) {
+        self.framebuffer_dump = Some(framebuffer_dump);
+    }
+
+    pub fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    /*
+     * Processes every remaining record of `demuxer`, then finishes the
+     * outputs. Errors are tagged with the record and its offset in the
+     * stream.
+     */
+    pub fn run<R: Read>(mut self, mut demuxer: Demuxer<R>) -> Result<SessionStats> {
+        while let Some(record) = demuxer.next_record()? {
+            self.process_record(&record).map_err(|err| err.in_record(record.index, demuxer.base() + record.offset))?;
+        }
+
+        self.finish()
+    }
+
+    pub fn process_record(&mut self, demuxed: &DemuxedRecord) -> Result<()> {
+        let record = demuxed.record()?;
+
+        match &record {
+            Record::Audio { format, header, data } => {
+                if let Some(audio_decoder) = self.audio_decoder.as_mut() {
+                    let pcmbuf = audio_decoder.decode(data, *format, header.samples)?;
+                    if let Some(audio_writer) = self.audio_writer.as_mut() {
+                        audio_writer.write_samples(&pcmbuf)?;
+                    }
+                }
+
+                self.stats.compressed_audio_size += demuxed.header.size;
+                self.stats.audio_record_count += 1;
+            },
+            _ => {
+                #[cfg(feature = "experimental-video")]
+                if let Some(video_decoder) = self.video_decoder.as_mut() {
+                    let picture = video_decoder.decode(record.data_format(), record.video_payload())?;
+
+                    if let Some(png_sequence) = self.png_sequence.as_mut() {
+                        png_sequence.write_frame(picture)?;
+                    }
+                    if let Some(framebuffer_dump) = self.framebuffer_dump.as_mut() {
+                        framebuffer_dump.write_frame(&video_decoder.framebuffer())?;
+                    }
+                }
+
+                self.stats.video_record_count += 1;
+            },
+        }
+
+        Ok(())
+    }
+
+    /* Flushes the outputs, the audio file gets its header */
+    pub fn finish(self) -> Result<SessionStats> {
+        if let Some(audio_writer) = self.audio_writer {
+            audio_writer.finish()?;
+        }
+        #[cfg(feature = "experimental-video")]
+        if let Some(framebuffer_dump) = self.framebuffer_dump {
+            framebuffer_dump.finish()?;
+        }
+
+        Ok(SessionStats {
+            #[cfg(feature = "experimental-video")]
+            displayed_frame_count: self.video_decoder.map(|video_decoder| video_decoder.frames_displayed()),
+            ..self.stats
+        })
+    }
+}
+
+impl<W: Write> Default for Session<W> {
+    fn default() -> Session<W> {
+        Session::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::audio::AudioEncoder;
+    use crate::error::Error;
+    use crate::export::AudioFileFormat;
+    use crate::hvqm::{DataFormat, HVQM2AudioHeader, HVQM2Header, HVQM2Record};
+    use crate::mux::HVQM2Writer;
+
+    /* A file of two audio records around a hold */
+    fn movie() -> Vec<u8> {
+        let mut header = HVQM2Header::for_video(16, 16, 2, 2, 4, 33333, 1);
+        header.samples_per_sec = 8000;
+        let samples: Vec<i16> = (0..300).map(|i| ((i * 53 % 1000) as i16 - 500) * 20).collect();
+
+        let mut encoder = AudioEncoder::new(&header).unwrap();
+        let mut writer = HVQM2Writer::new(Cursor::new(Vec::new()), &header).unwrap();
+        writer.write_record(DataFormat::AudioKeyframe, &encoder.encode_record(DataFormat::AudioKeyframe, &samples[..100]).unwrap()).unwrap();
+        writer.write_record(DataFormat::VideoHold, &[]).unwrap();
+        writer.write_record(DataFormat::AudioPredict, &encoder.encode_record(DataFormat::AudioPredict, &samples[100..]).unwrap()).unwrap();
+        writer.finish_with_summary().unwrap().into_inner()
+    }
+
+    #[test]
+    fn audio_is_decoded_and_records_counted() {
+        let file = movie();
+        let demuxer = Demuxer::new(Cursor::new(&file)).unwrap();
+        let header = demuxer.header().clone();
+
+        let mut decoder = AudioDecoder::new(&header).unwrap();
+        let mut expected = Vec::new();
+        for record in Demuxer::new(Cursor::new(&file)).unwrap() {
+            let record = record.unwrap();
+            if let Record::Audio { format, header, data } = record.record().unwrap() {
+                expected.extend(decoder.decode(data, format, header.samples).unwrap().iter().flat_map(|sample| sample.to_be_bytes()));
+            }
+        }
+
+        let mut output = Vec::new();
+        let mut session = Session::new();
+        session.set_audio_output(AudioDecoder::new(&header).unwrap(), AudioWriter::new(&mut output, AudioFileFormat::RawS16Be, 1, 8000));
+        let stats = session.run(demuxer).unwrap();
+
+        assert_eq!((stats.audio_record_count, stats.video_record_count), (2, 1));
+        /* The hold has no data, everything else after the headers is audio */
+        assert_eq!(stats.compressed_audio_size as usize, file.len() - HVQM2Header::SIZE - 3 * HVQM2Record::SIZE);
+        assert_eq!(stats.displayed_frame_count, None);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn errors_give_the_offset_in_the_stream() {
+        let file = movie();
+        let mut image = vec![0xFF; 0x20];
+        image.extend(&file);
+        /* Step index 0x7F in the first audio record */
+        image[0x20 + HVQM2Header::SIZE + HVQM2Record::SIZE + HVQM2AudioHeader::SIZE + 1] = 0x7F;
+
+        let demuxer = Demuxer::at_offset(Cursor::new(&image), 0x20).unwrap();
+        let mut session: Session<Vec<u8>> = Session::new();
+        session.set_audio_output(AudioDecoder::new(demuxer.header()).unwrap(), AudioWriter::new(Vec::new(), AudioFileFormat::RawS16Be, 1, 8000));
+
+        match session.run(demuxer) {
+            Err(Error::InRecord { index: 0, offset, source }) => {
+                assert_eq!(offset, 0x20 + HVQM2Header::SIZE as u64);
+                assert!(matches!(*source, Error::InvalidStepIndex { value: 0x7F }));
+            },
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use std::fmt;
+
+use crate::error::Error;
+use crate::hvqm::{AudioFormat, DataFormat, HVQM2AudioHeader, HVQM2Header, HVQM2Record, Record, RecordType, Records};
+
+/*
+ * Container : What surrounds the data given to `validate`
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Container {
+    File,        /* The data is the whole file, its length must be `file_size` (with or without zero padding) */
+    Embedded,    /* The data is followed by unrelated bytes (e.g. in a ROM image) */
+}
+
+/*
+ * Problem : One inconsistency, at `offset` from the start of the file header
+ */
+#[derive(Debug)]
+pub struct Problem {
+    pub offset: u64,
+    pub kind: ProblemKind,
+}
+
+#[derive(Debug)]
+pub enum ProblemKind {
+    FileSize { declared: u32, actual: u64 },
+    TotalFrames { declared: u32, counted: u32 },
+    TotalAudioRecords { declared: u32, counted: u32 },
+    MaxFrameSize { declared: u32, observed: u32 },
+    MaxAudioRecordSize { declared: u32, observed: u32 },
+    /* The audio data doesn't hold exactly `samples` samples per channel */
+    AudioSize { samples: u32, expected: usize, actual: usize },
+    /* A record or section that can't be read; nothing past a record that can't be read is checked */
+    Error(Error),
+}
+
+impl Problem {
+    /* Short stable identifier of the kind of problem */
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            ProblemKind::FileSize { .. } => "file_size",
+            ProblemKind::TotalFrames { .. } => "total_frames",
+            ProblemKind::TotalAudioRecords { .. } => "total_audio_records",
+            ProblemKind::MaxFrameSize { .. } => "max_frame_size",
+            ProblemKind::MaxAudioRecordSize { .. } => "max_audio_record_size",
+            ProblemKind::AudioSize { .. } => "audio_size",
+            ProblemKind::Error(_) => "error",
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ProblemKind::FileSize { declared, actual } => write!(f, "header file_size is {declared} but the file is {actual} bytes"),
+            ProblemKind::TotalFrames { declared, counted } => write!(f, "header total_frames is {declared} but there are {counted} video records"),
+            ProblemKind::TotalAudioRecords { declared, counted } => write!(f, "header total_audio_records is {declared} but there are {counted} audio records"),
+            ProblemKind::MaxFrameSize { declared, observed } => write!(f, "header max_frame_size is {declared} but the largest video record is {observed} bytes"),
+            ProblemKind::MaxAudioRecordSize { declared, observed } => write!(f, "header max_audio_record_size is {declared} but the largest audio record is {observed} bytes"),
+            ProblemKind::AudioSize { samples, expected, actual } => write!(f, "{samples} samples need {expected} bytes of audio data, record has {actual}"),
+            ProblemKind::Error(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/*
+ * Checks the header against the records and every record against itself.
+ * `buf` starts with the file header.
+ *
+ * Only the layout is checked: sizes, counts, audio sample counts and video
+ * section offsets. Nothing is decoded, so the limits of the decoders don't
+ * make a file invalid.
+ *
+ * All problems are returned, not only the first one, sorted by offset.
+ * An empty list means the file is consistent.
+ */
+pub fn validate(buf: &[u8], container: Container) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let header = match HVQM2Header::parse(buf).and_then(|header| header.check_magic().map(|()| header)) {
+        Ok(header) => header,
+        Err(err) => {
+            problems.push(Problem { offset: 0, kind: ProblemKind::Error(err) });
+            return problems;
+        },
+    };
+
+    /* Embedded data ends at `file_size`, a file at its real end */
+    let file_size = header.file_size as usize;
+    let end = match container {
+        Container::File => buf.len(),
+        Container::Embedded => file_size.min(buf.len()),
+    };
+
+    let mut audio_records = 0;
+    let mut video_records = 0;
+    let mut max_audio_size = 0;
+    let mut max_frame_size = 0;
+    let mut complete = true;
+
+    let mut records = Records::new(&buf[..end]);
+    for entry in &mut records {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                let offset = match &err {
+                    Error::InRecord { offset, .. } => *offset,
+                    _ => 0,
+                };
+                problems.push(Problem { offset, kind: ProblemKind::Error(err) });
+                complete = false;
+                break;
+            },
+        };
+
+        match entry.record.record_type() {
+            RecordType::Audio => {
+                audio_records += 1;
+                max_audio_size = max_audio_size.max(entry.header.size);
+            },
+            RecordType::Video => {
+                video_records += 1;
+                max_frame_size = max_frame_size.max(entry.header.size);
+            },
+        }
+
+        match &entry.record {
+            Record::Audio { format, header: audio_header, data } => {
+                if let Some(expected) = audio_data_size(&header, *format, audio_header.samples) {
+                    if expected != data.len() {
+                        let offset = entry.offset + (HVQM2Record::SIZE + HVQM2AudioHeader::SIZE) as u64;
+                        problems.push(Problem { offset, kind: ProblemKind::AudioSize { samples: audio_header.samples, expected, actual: data.len() } });
+                    }
+                }
+            },
+            Record::VideoHold => (),
+            record => {
+                if let Err(err) = record.sections() {
+                    problems.push(Problem { offset: entry.offset, kind: ProblemKind::Error(err.in_record(entry.index, entry.offset)) });
+                }
+            },
+        }
+    }
+
+    /* A padded file's size can be given with or without the padding */
+    let size_matches = match container {
+        Container::File => file_size == buf.len() || (records.padding() > 0 && file_size == buf.len() - records.padding()),
+        Container::Embedded => file_size <= buf.len(),
+    };
+    if !size_matches {
+        problems.push(Problem { offset: 0x10, kind: ProblemKind::FileSize { declared: header.file_size, actual: buf.len() as u64 } });
+    }
+
+    /* Counts are meaningless if the records could not all be read */
+    if !complete {
+        problems.sort_by_key(|problem| problem.offset);
+        return problems;
+    }
+
+    if header.total_frames != video_records {
+        problems.push(Problem { offset: 0x1C, kind: ProblemKind::TotalFrames { declared: header.total_frames, counted: video_records } });
+    }
+    if header.max_frame_size != max_frame_size {
+        problems.push(Problem { offset: 0x24, kind: ProblemKind::MaxFrameSize { declared: header.max_frame_size, observed: max_frame_size } });
+    }
+    if header.total_audio_records != audio_records {
+        problems.push(Problem { offset: 0x30, kind: ProblemKind::TotalAudioRecords { declared: header.total_audio_records, counted: audio_records } });
+    }
+    if header.max_audio_record_size != max_audio_size {
+        problems.push(Problem { offset: 0x38, kind: ProblemKind::MaxAudioRecordSize { declared: header.max_audio_record_size, observed: max_audio_size } });
+    }
+
+    problems.sort_by_key(|problem| problem.offset);
+    problems
+}
+
+/*
+ * Size of the audio data (after the audio header) holding `samples`
+ * samples, or `None` if the header's audio format is unusable or the audio
+ * is not mono (the layout of other channel counts is not known)
+ */
+fn audio_data_size(header: &HVQM2Header, format: DataFormat, samples: u32) -> Option<usize> {
+    if header.channels != 1 {
+        return None;
+    }
+
+    match header.audio_data_format().ok()? {
+        AudioFormat::Adpcm => Some(format.to_adpcm_format().ok()?.encoded_size(samples)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::hvqm::{HVQM2Frame, HVQM2KeyFrame};
+    use crate::mux::HVQM2Writer;
+
+    /* Key frame record data with every section starting right after the headers, followed by `extra` bytes */
+    fn key_frame(section_offset: u32, extra: usize) -> Vec<u8> {
+        let frame = HVQM2Frame {
+            basisnum_offset: [section_offset; 2],
+            basnumrn_offset: [section_offset; 2],
+            scale_offset: [section_offset; 3],
+            fixvl_offset: [section_offset; 3],
+            dcval_offset: [section_offset; 3],
+        };
+        let key_frame = HVQM2KeyFrame { dcrun_offset: [section_offset; 3], nest_start_x: 0, nest_start_y: 0 };
+
+        let mut data = frame.to_bytes().to_vec();
+        data.extend(key_frame.to_bytes());
+        data.resize(data.len() + extra, 0);
+        data
+    }
+
+    /* ADPCM record data of `samples` samples with `size` bytes after the audio header */
+    fn audio(samples: u32, size: usize) -> Vec<u8> {
+        let mut data = HVQM2AudioHeader { samples }.to_bytes().to_vec();
+        data.resize(HVQM2AudioHeader::SIZE + size, 0);
+        data
+    }
+
+    /* A file of `records` with a header matching them */
+    fn file(records: &[(DataFormat, Vec<u8>)]) -> Vec<u8> {
+        let header = HVQM2Header::for_video(16, 16, 2, 2, 4, 33_333, 1);
+        let mut writer = HVQM2Writer::new(Cursor::new(Vec::new()), &header).unwrap();
+        for (format, data) in records {
+            writer.write_record(*format, data).unwrap();
+        }
+        writer.finish_with_summary().unwrap().into_inner()
+    }
+
+    const SECTIONS: u32 = (HVQM2Frame::SIZE + HVQM2KeyFrame::SIZE) as u32;
+
+    fn names(problems: &[Problem]) -> Vec<(u64, &'static str)> {
+        problems.iter().map(|problem| (problem.offset, problem.name())).collect()
+    }
+
+    #[test]
+    fn consistent_file_has_no_problems() {
+        let buf = file(&[
+            (DataFormat::AudioKeyframe, audio(5, 4)),
+            (DataFormat::VideoKeyframe, key_frame(SECTIONS, 16)),
+            (DataFormat::AudioPredict, audio(6, 3)),
+            (DataFormat::VideoHold, Vec::new()),
+        ]);
+        assert!(validate(&buf, Container::File).is_empty());
+    }
+
+    #[test]
+    fn stale_header_fields_are_reported() {
+        let mut buf = file(&[(DataFormat::AudioKeyframe, audio(5, 4)), (DataFormat::VideoKeyframe, key_frame(SECTIONS, 16))]);
+        for offset in [0x10, 0x1C, 0x24, 0x30, 0x38] {
+            buf[offset + 3] ^= 0x40;
+        }
+        assert_eq!(names(&validate(&buf, Container::File)), [
+            (0x10, "file_size"),
+            (0x1C, "total_frames"),
+            (0x24, "max_frame_size"),
+            (0x30, "total_audio_records"),
+            (0x38, "max_audio_record_size"),
+        ]);
+    }
+
+    #[test]
+    fn audio_size_is_checked_against_the_sample_count() {
+        let buf = file(&[(DataFormat::AudioKeyframe, audio(5, 4)), (DataFormat::AudioPredict, audio(6, 4))]);
+        let problems = validate(&buf, Container::File);
+        let offset = (HVQM2Header::SIZE + 2 * HVQM2Record::SIZE + HVQM2AudioHeader::SIZE + 4 + HVQM2AudioHeader::SIZE) as u64;
+        assert_eq!(names(&problems), [(offset, "audio_size")]);
+        assert!(matches!(problems[0].kind, ProblemKind::AudioSize { samples: 6, expected: 3, actual: 4 }));
+    }
+
+    #[test]
+    fn stereo_audio_size_is_not_checked() {
+        let mut buf = file(&[(DataFormat::AudioKeyframe, audio(5, 4))]);
+        buf[0x2D] = 2;
+        assert!(validate(&buf, Container::File).is_empty());
+    }
+
+    #[test]
+    fn section_outside_of_the_record_is_reported() {
+        let buf = file(&[(DataFormat::VideoKeyframe, key_frame(SECTIONS + 17, 16)), (DataFormat::VideoHold, Vec::new())]);
+        let problems = validate(&buf, Container::File);
+        assert_eq!(names(&problems), [(HVQM2Header::SIZE as u64, "error")]);
+        assert!(matches!(&problems[0].kind, ProblemKind::Error(Error::InRecord { source, .. }) if matches!(**source, Error::OffsetOutOfRange { .. })));
+    }
+
+    #[test]
+    fn truncated_record_stops_the_checks() {
+        let mut buf = file(&[(DataFormat::VideoKeyframe, key_frame(SECTIONS, 16)), (DataFormat::AudioKeyframe, audio(5, 4))]);
+        buf.truncate(buf.len() - 2);
+        let problems = validate(&buf, Container::File);
+        let record = (HVQM2Header::SIZE + HVQM2Record::SIZE) as u64 + SECTIONS as u64 + 16;
+        /* The header file_size is wrong, the counts are not checked */
+        assert_eq!(names(&problems), [(0x10, "file_size"), (record, "error")]);
+    }
+
+    #[test]
+    fn embedded_data_ends_at_file_size() {
+        let mut buf = file(&[(DataFormat::VideoKeyframe, key_frame(SECTIONS, 16))]);
+        buf.extend([0xFF; 32]);
+        assert!(validate(&buf, Container::Embedded).is_empty());
+        /* As a file, the trailing bytes are read as a record too */
+        assert_eq!(names(&validate(&buf, Container::File))[0], (0x10, "file_size"));
+    }
+
+    #[test]
+    fn zero_padding_is_accepted() {
+        let mut buf = file(&[(DataFormat::AudioKeyframe, audio(5, 4)), (DataFormat::VideoHold, Vec::new())]);
+        buf.extend([0; 64]);
+        assert!(validate(&buf, Container::File).is_empty());
+
+        /* file_size counting the padding */
+        let file_size = buf.len() as u32;
+        buf[0x10..0x14].copy_from_slice(&file_size.to_be_bytes());
+        assert!(validate(&buf, Container::File).is_empty());
+        assert!(validate(&buf, Container::Embedded).is_empty());
+    }
+}
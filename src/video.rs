@@ -0,0 +1,694 @@
+use crate::color::{self, PixelFormat};
+use crate::error::{Error, Result};
+use crate::hvqm::{DataFormat, FrameSections, HVQM2Frame, HVQM2Header, HVQM2KeyFrame};
+
+pub const NEST_SIZE_L: usize = 70;    /* Number of elements on long side of nest */
+pub const NEST_SIZE_S: usize = 38;    /* Number of elements on short side of nest */
+pub const MAX_QUANTIZE_SHIFT: u8 = 27;    /* Largest video_quantize_shift, basis sums are shifted by 4 more bits */
+
+pub(crate) const BLOCK_SIZE: usize = 4;          /* Blocks are 4x4 pixels in every plane */
+pub(crate) const MAX_AOT_BASES: u8 = 7;          /* Basis numbers 1..=7 select that many nest bases */
+pub(crate) const ORIGINAL_BLOCK: u8 = 8;         /* Basis number of a block stored as raw pixels */
+
+/* Macroblock state flags of predicted frames (2 bits per macroblock) */
+pub(crate) const MB_SKIP: u8 = 0;                /* Copy of the same macroblock of the previous picture */
+pub(crate) const MB_INTER: u8 = 1;               /* Motion compensated copy plus residual */
+pub(crate) const MB_INTRA: u8 = 2;               /* Coded like a key frame block, with a raw DC */
+
+/*
+ * Plane : One 8-bit image component
+ */
+#[derive(Clone)]
+pub struct Plane {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Plane {
+    pub fn new(width: usize, height: usize) -> Plane {
+        Plane {
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: u8) {
+        self.pixels[y * self.width + x] = value;
+    }
+
+    pub(crate) fn blocks_wide(&self) -> usize {
+        self.width / BLOCK_SIZE
+    }
+
+    pub(crate) fn blocks_high(&self) -> usize {
+        self.height / BLOCK_SIZE
+    }
+}
+
+/*
+ * Picture : A reconstructed frame
+ *
+ * The planes are allocated rounded up to whole macroblocks, `width` and
+ * `height` are the displayed size from the file header.
+ */
+#[derive(Clone)]
+pub struct Picture {
+    pub width: usize,
+    pub height: usize,
+    pub h_sampling_rate: usize,    /* Luma pixels per chroma sample horizontally */
+    pub v_sampling_rate: usize,    /* Luma pixels per chroma sample vertically */
+    pub y: Plane,
+    pub u: Plane,
+    pub v: Plane,
+}
+
+impl Picture {
+    pub fn new(width: usize, height: usize, h_sampling_rate: usize, v_sampling_rate: usize) -> Picture {
+        let mcu_width = BLOCK_SIZE * h_sampling_rate;
+        let mcu_height = BLOCK_SIZE * v_sampling_rate;
+        let luma_width = width.div_ceil(mcu_width) * mcu_width;
+        let luma_height = height.div_ceil(mcu_height) * mcu_height;
+
+        Picture {
+            width,
+            height,
+            h_sampling_rate,
+            v_sampling_rate,
+            y: Plane::new(luma_width, luma_height),
+            u: Plane::new(luma_width / h_sampling_rate, luma_height / v_sampling_rate),
+            v: Plane::new(luma_width / h_sampling_rate, luma_height / v_sampling_rate),
+        }
+    }
+
+    pub fn plane(&self, index: usize) -> &Plane {
+        match index {
+            0 => &self.y,
+            1 => &self.u,
+            _ => &self.v,
+        }
+    }
+
+    pub(crate) fn plane_mut(&mut self, index: usize) -> &mut Plane {
+        match index {
+            0 => &mut self.y,
+            1 => &mut self.u,
+            _ => &mut self.v,
+        }
+    }
+}
+
+/*
+ * Sequential reader over one section of a video record.
+ * Error offsets are relative to the start of the section.
+ */
+struct ByteStream<'a> {
+    name: &'static str,
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteStream<'a> {
+    fn new(name: &'static str, section: &'a [u8]) -> ByteStream<'a> {
+        ByteStream {
+            name,
+            buf: section,
+            pos: 0,
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        match self.buf.get(self.pos..self.pos + N) {
+            Some(bytes) => {
+                self.pos += N;
+                Ok(bytes.try_into().unwrap())
+            },
+            None => Err(Error::Truncated {
+                structure: self.name,
+                offset: self.pos as u64,
+                needed: N,
+                available: self.buf.len().saturating_sub(self.pos),
+            }),
+        }
+    }
+
+    fn next_u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn next_i8(&mut self) -> Result<i8> {
+        Ok(self.next_u8()? as i8)
+    }
+
+    fn next_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+}
+
+/*
+ * Reader of the 2-bit macroblock state flags, most significant bits first
+ */
+struct FlagStream<'a> {
+    bytes: ByteStream<'a>,
+    current: u8,
+    bits_left: u32,
+}
+
+impl FlagStream<'_> {
+    fn new(section: &[u8]) -> FlagStream<'_> {
+        FlagStream {
+            bytes: ByteStream::new("macroblock", section),
+            current: 0,
+            bits_left: 0,
+        }
+    }
+
+    fn next_flag(&mut self) -> Result<u8> {
+        if self.bits_left == 0 {
+            self.current = self.bytes.next_u8()?;
+            self.bits_left = 8;
+        }
+
+        self.bits_left -= 2;
+        Ok((self.current >> self.bits_left) & 3)
+    }
+}
+
+/*
+ * Value stream with zero run-length ("cold run") compression.
+ * A zero read from `values` is followed by a byte in `runs` telling how
+ * many more zeroes are implied before the next stored value.
+ *
+ * Assumed meaning of the basnumrn/dcrun sections, not checked against the
+ * SDK or a real file.
+ */
+struct RunStream<'a> {
+    values: ByteStream<'a>,
+    runs: ByteStream<'a>,
+    zero_run: u32,
+}
+
+impl<'a> RunStream<'a> {
+    fn new(values: ByteStream<'a>, runs: ByteStream<'a>) -> RunStream<'a> {
+        RunStream {
+            values,
+            runs,
+            zero_run: 0,
+        }
+    }
+
+    fn basisnum(sections: &FrameSections<'a>, index: usize) -> RunStream<'a> {
+        RunStream::new(
+            ByteStream::new("basisnum", sections.basisnum[index]),
+            ByteStream::new("basnumrn", sections.basnumrn[index]),
+        )
+    }
+
+    fn next_u8(&mut self) -> Result<u8> {
+        if self.zero_run > 0 {
+            self.zero_run -= 1;
+            return Ok(0);
+        }
+
+        let value = self.values.next_u8()?;
+        if value == 0 {
+            self.zero_run = self.runs.next_u8()? as u32;
+        }
+        Ok(value)
+    }
+}
+
+/*
+ * Nest : Downscaled luma image used as the codebook of AOT bases
+ */
+#[derive(Clone)]
+pub(crate) struct Nest {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Nest {
+    pub(crate) fn new() -> Nest {
+        Nest {
+            width: 0,
+            height: 0,
+            pixels: Vec::new(),
+        }
+    }
+
+    /*
+     * Rebuilds the nest from the luma block DC image, starting at
+     * (`start_x`, `start_y`) and wrapping around its edges.
+     */
+    pub(crate) fn build(&mut self, dc: &Plane, start_x: usize, start_y: usize) {
+        self.width = NEST_SIZE_L.min(dc.width);
+        self.height = NEST_SIZE_S.min(dc.height);
+        self.pixels.clear();
+
+        for j in 0..self.height {
+            for i in 0..self.width {
+                self.pixels.push(dc.get((start_x + i) % dc.width, (start_y + j) % dc.height));
+            }
+        }
+    }
+
+    /*
+     * Extracts the zero-mean 4x4 basis described by a fixed length code,
+     * scaled up by 16 so the mean can be removed without rounding.
+     * Assumed code layout, not checked against the SDK or a real file:
+     *   bits 0-6  : x position in the nest
+     *   bits 7-12 : y position in the nest
+     *   bit 13    : horizontal step (0: 1 element, 1: 2 elements)
+     *   bit 14    : vertical step (0: 1 element, 1: 2 elements)
+     */
+    pub(crate) fn basis(&self, code: u16) -> Result<[i32; 16]> {
+        if self.pixels.is_empty() {
+            return Err(Error::MissingKeyframe);
+        }
+
+        let x = (code & 0x7F) as usize;
+        let y = ((code >> 7) & 0x3F) as usize;
+        let step_x = 1 + ((code >> 13) & 1) as usize;
+        let step_y = 1 + ((code >> 14) & 1) as usize;
+
+        let mut basis = [0; 16];
+        let mut sum = 0;
+        for j in 0..BLOCK_SIZE {
+            for i in 0..BLOCK_SIZE {
+                let nx = (x + i * step_x) % self.width;
+                let ny = (y + j * step_y) % self.height;
+                let value = self.pixels[ny * self.width + nx] as i32;
+
+                basis[j * BLOCK_SIZE + i] = value;
+                sum += value;
+            }
+        }
+
+        for value in basis.iter_mut() {
+            *value = *value * 16 - sum;
+        }
+        Ok(basis)
+    }
+}
+
+/*
+ * Per-plane streams of a video record
+ */
+struct PlaneStreams<'a> {
+    scale: ByteStream<'a>,
+    fixvl: ByteStream<'a>,
+}
+
+impl PlaneStreams<'_> {
+    fn new<'a>(sections: &FrameSections<'a>, plane: usize) -> PlaneStreams<'a> {
+        PlaneStreams {
+            scale: ByteStream::new("scale", sections.scale[plane]),
+            fixvl: ByteStream::new("fixvl", sections.fixvl[plane]),
+        }
+    }
+}
+
+/*
+ * VideoDecoder : Reconstructs pictures from video records
+ *
+ * The layout of the video sections (block order, basis numbers, fixed
+ * length codes, DC prediction, macroblock flags) is a reconstruction: no
+ * SDK source or real N64 file was available to check it against. Files
+ * written by `encode` use the same layout, so they decode here, but real
+ * files may not decode correctly.
+ *
+ * `y_shiftnum` (the first nest row, from its name) is not understood, so
+ * key frames of files where it isn't 0 are rejected rather than decoded
+ * with a wrong nest.
+ */
+#[derive(Clone)]
+pub struct VideoDecoder {
+    pixel_format: PixelFormat,
+    usec_per_frame: u32,
+    frames_displayed: u32,
+    h_sampling_rate: usize,
+    v_sampling_rate: usize,
+    quantize_shift: u32,
+    y_shiftnum: u8,
+    keyframe_decoded: bool,    /* Whether `current` holds a decoded picture */
+    nest: Nest,
+    current: Picture,
+    previous: Picture,
+}
+
+impl VideoDecoder {
+    /* Fails if the header's quantize shift can't be applied */
+    pub fn new(header: &HVQM2Header) -> Result<VideoDecoder> {
+        if header.video_quantize_shift > MAX_QUANTIZE_SHIFT {
+            return Err(Error::UnsupportedQuantizeShift { value: header.video_quantize_shift, max: MAX_QUANTIZE_SHIFT });
+        }
+
+        let h_sampling_rate = header.h_sampling_rate.max(1) as usize;
+        let v_sampling_rate = header.v_sampling_rate.max(1) as usize;
+
+        Ok(VideoDecoder {
+            pixel_format: PixelFormat::Rgba5551,
+            usec_per_frame: header.usec_per_frame,
+            frames_displayed: 0,
+            h_sampling_rate,
+            v_sampling_rate,
+            quantize_shift: header.video_quantize_shift as u32,
+            y_shiftnum: header.y_shiftnum,
+            keyframe_decoded: false,
+            nest: Nest::new(),
+            current: Picture::new(header.width as usize, header.height as usize, h_sampling_rate, v_sampling_rate),
+            previous: Picture::new(header.width as usize, header.height as usize, h_sampling_rate, v_sampling_rate),
+        })
+    }
+
+    /*
+     * Decodes any video record. Every record, including holds, displays
+     * exactly one picture for one frame interval.
+     */
+    pub fn decode(&mut self, format: DataFormat, payload: &[u8]) -> Result<&Picture> {
+        match format {
+            DataFormat::VideoKeyframe => self.decode_keyframe(payload),
+            DataFormat::VideoPredict => self.decode_predict(payload),
+            DataFormat::VideoHold => self.hold(),
+            _ => Err(Error::UnexpectedFormat { expected: "video", found: format }),
+        }
+    }
+
+    /* Last displayed picture */
+    pub fn picture(&self) -> &Picture {
+        &self.current
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /* Selects the framebuffer format produced by `framebuffer` (RGBA5551 by default) */
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.pixel_format = format;
+    }
+
+    /* Last displayed picture converted to the selected pixel format (with the stand-in colors of `color`) */
+    pub fn framebuffer(&self) -> Vec<u8> {
+        color::picture_to_framebuffer(&self.current, self.pixel_format)
+    }
+
+    /* Number of pictures displayed so far (hold records included) */
+    pub fn frames_displayed(&self) -> u32 {
+        self.frames_displayed
+    }
+
+    /*
+     * Sets the number of pictures displayed before the next record, to
+     * keep presentation times right when decoding starts at a key frame in
+     * the middle of the stream
+     */
+    pub fn set_frames_displayed(&mut self, frames: u32) {
+        self.frames_displayed = frames;
+    }
+
+    /* Presentation time of the last displayed picture [usec.] */
+    pub fn presentation_time(&self) -> u64 {
+        self.frames_displayed.saturating_sub(1) as u64 * self.usec_per_frame as u64
+    }
+
+    /*
+     * Handles a VideoHold record: the previous picture is shown again for
+     * one more frame interval. There is none before the first key frame.
+     */
+    pub fn hold(&mut self) -> Result<&Picture> {
+        if !self.keyframe_decoded {
+            return Err(Error::MissingKeyframe);
+        }
+
+        self.frames_displayed += 1;
+        Ok(&self.current)
+    }
+
+    /*
+     * Decodes a VideoKeyframe record.
+     * `payload` is the record data following the record header.
+     */
+    pub fn decode_keyframe(&mut self, payload: &[u8]) -> Result<&Picture> {
+        if self.y_shiftnum != 0 {
+            return Err(Error::UnsupportedYShift { value: self.y_shiftnum });
+        }
+
+        let frame = HVQM2Frame::parse(payload)?;
+        let key_frame = HVQM2KeyFrame::parse_at(payload, HVQM2Frame::SIZE)?;
+        let sections = frame.sections(payload, DataFormat::VideoKeyframe)?;
+
+        let mut dc_planes = Vec::with_capacity(3);
+        for plane in 0..3 {
+            let mut dcval = RunStream::new(
+                ByteStream::new("dcval", sections.dcval[plane]),
+                ByteStream::new("dcrun", sections.dcrun[plane]),
+            );
+            dc_planes.push(decode_dc_plane(self.current.plane(plane), &mut dcval)?);
+        }
+
+        self.nest.build(&dc_planes[0], key_frame.nest_start_x as usize, key_frame.nest_start_y as usize);
+        self.keyframe_decoded = true;
+
+        let mut basisnum = [
+            RunStream::basisnum(&sections, 0),
+            RunStream::basisnum(&sections, 1),
+        ];
+
+        for (plane, dc) in dc_planes.iter().enumerate() {
+            let mut streams = PlaneStreams::new(&sections, plane);
+            let basisnum = &mut basisnum[plane.min(1)];
+            let target = self.current.plane_mut(plane);
+
+            for by in 0..dc.height {
+                for bx in 0..dc.width {
+                    let basis_count = basisnum.next_u8()?;
+                    let block = decode_block(&self.nest, self.quantize_shift, [dc.get(bx, by) as i32; 16], basis_count, &mut streams)?;
+                    store_block(target, bx, by, &block);
+                }
+            }
+        }
+
+        self.frames_displayed += 1;
+        Ok(&self.current)
+    }
+
+    /*
+     * Decodes a VideoPredict record on top of the previously decoded picture.
+     * The AOT bases are taken from the nest of the last key frame, so one
+     * must have been decoded first.
+     */
+    pub fn decode_predict(&mut self, payload: &[u8]) -> Result<&Picture> {
+        if !self.keyframe_decoded {
+            return Err(Error::MissingKeyframe);
+        }
+
+        let frame = HVQM2Frame::parse(payload)?;
+        let sections = frame.sections(payload, DataFormat::VideoPredict)?;
+
+        std::mem::swap(&mut self.current, &mut self.previous);
+
+        let mut flags = FlagStream::new(sections.macroblock);
+        let mut movevector = ByteStream::new("movevector", sections.movevector);
+        let mut basisnum = [
+            RunStream::basisnum(&sections, 0),
+            RunStream::basisnum(&sections, 1),
+        ];
+        let mut dcval = [
+            ByteStream::new("dcval", sections.dcval[0]),
+            ByteStream::new("dcval", sections.dcval[1]),
+            ByteStream::new("dcval", sections.dcval[2]),
+        ];
+        let mut streams = [
+            PlaneStreams::new(&sections, 0),
+            PlaneStreams::new(&sections, 1),
+            PlaneStreams::new(&sections, 2),
+        ];
+
+        let mcus_wide = self.current.u.blocks_wide();
+        let mcus_high = self.current.u.blocks_high();
+
+        for mcu_y in 0..mcus_high {
+            for mcu_x in 0..mcus_wide {
+                let state = flags.next_flag()?;
+                let (dx, dy) = match state {
+                    MB_INTER => (movevector.next_i8()? as isize, movevector.next_i8()? as isize),
+                    _ => (0, 0),
+                };
+
+                for plane in 0..3 {
+                    let (blocks_x, blocks_y, mv_x, mv_y) = if plane == 0 {
+                        (self.h_sampling_rate, self.v_sampling_rate, dx, dy)
+                    } else {
+                        (1, 1, dx.div_euclid(self.h_sampling_rate as isize), dy.div_euclid(self.v_sampling_rate as isize))
+                    };
+
+                    for j in 0..blocks_y {
+                        for i in 0..blocks_x {
+                            let bx = mcu_x * blocks_x + i;
+                            let by = mcu_y * blocks_y + j;
+                            let reference = self.previous.plane(plane);
+
+                            let block = match state {
+                                MB_SKIP => motion_compensate(reference, bx, by, 0, 0).map(|value| value as u8),
+                                MB_INTER => {
+                                    let mut prediction = motion_compensate(reference, bx, by, mv_x, mv_y);
+                                    let dc = dcval[plane].next_i8()? as i32;
+                                    for value in prediction.iter_mut() {
+                                        *value += dc;
+                                    }
+
+                                    let basis_count = basisnum[plane.min(1)].next_u8()?;
+                                    decode_block(&self.nest, self.quantize_shift, prediction, basis_count, &mut streams[plane])?
+                                },
+                                MB_INTRA => {
+                                    let dc = dcval[plane].next_u8()? as i32;
+                                    let basis_count = basisnum[plane.min(1)].next_u8()?;
+                                    decode_block(&self.nest, self.quantize_shift, [dc; 16], basis_count, &mut streams[plane])?
+                                },
+                                _ => return Err(Error::InvalidMacroblockState { value: state }),
+                            };
+
+                            store_block(self.current.plane_mut(plane), bx, by, &block);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.frames_displayed += 1;
+        Ok(&self.current)
+    }
+}
+
+
+/*
+ * Fetches the reference block displaced by (`mv_x`, `mv_y`) pixels,
+ * clamping coordinates that fall outside the plane to its edges.
+ */
+pub(crate) fn motion_compensate(reference: &Plane, bx: usize, by: usize, mv_x: isize, mv_y: isize) -> [i32; 16] {
+    let mut block = [0; 16];
+
+    for j in 0..BLOCK_SIZE {
+        for i in 0..BLOCK_SIZE {
+            let x = ((bx * BLOCK_SIZE + i) as isize + mv_x).clamp(0, reference.width as isize - 1);
+            let y = ((by * BLOCK_SIZE + j) as isize + mv_y).clamp(0, reference.height as isize - 1);
+            block[j * BLOCK_SIZE + i] = reference.get(x as usize, y as usize) as i32;
+        }
+    }
+
+    block
+}
+
+/*
+ * Decodes the DC value of every block of a plane into a block-sized image.
+ * Each DC is stored as a delta from its left neighbour (or the block above
+ * for the first column, or 128 for the first block). Assumed prediction,
+ * not checked against the SDK or a real file.
+ */
+fn decode_dc_plane(plane: &Plane, dcval: &mut RunStream) -> Result<Plane> {
+    let mut dc = Plane::new(plane.blocks_wide(), plane.blocks_high());
+
+    for by in 0..dc.height {
+        for bx in 0..dc.width {
+            let predictor = if bx > 0 {
+                dc.get(bx - 1, by)
+            } else if by > 0 {
+                dc.get(bx, by - 1)
+            } else {
+                0x80
+            };
+
+            dc.set(bx, by, predictor.wrapping_add(dcval.next_u8()?));
+        }
+    }
+
+    Ok(dc)
+}
+
+/*
+ * Reconstructs the 16 pixels of one block by adding the AOT bases selected
+ * by its basis number to `prediction` (the block DC, plus the motion
+ * compensated pixels in predicted macroblocks).
+ */
+fn decode_block(nest: &Nest, quantize_shift: u32, prediction: [i32; 16], basis_count: u8, streams: &mut PlaneStreams) -> Result<[u8; 16]> {
+    match basis_count {
+        0 => Ok(prediction.map(|value| value.clamp(0, 255) as u8)),
+        1..=MAX_AOT_BASES => {
+            let mut sum = [0i32; 16];
+            for _ in 0..basis_count {
+                let code = streams.fixvl.next_u16()?;
+                let scale = streams.scale.next_i8()? as i32;
+                let basis = nest.basis(code)?;
+
+                for (acc, value) in sum.iter_mut().zip(basis) {
+                    *acc += scale * value;
+                }
+            }
+
+            let mut block = [0u8; 16];
+            for ((pixel, acc), base) in block.iter_mut().zip(sum).zip(prediction) {
+                *pixel = (base + (acc >> (quantize_shift + 4))).clamp(0, 255) as u8;
+            }
+            Ok(block)
+        },
+        ORIGINAL_BLOCK => streams.fixvl.take(),
+        _ => Err(Error::InvalidBasisNumber { value: basis_count }),
+    }
+}
+
+pub(crate) fn store_block(plane: &mut Plane, bx: usize, by: usize, block: &[u8; 16]) {
+    for j in 0..BLOCK_SIZE {
+        for i in 0..BLOCK_SIZE {
+            plane.set(bx * BLOCK_SIZE + i, by * BLOCK_SIZE + j, block[j * BLOCK_SIZE + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::VideoEncoder;
+
+    #[test]
+    fn predicted_frame_needs_a_key_frame() {
+        let header = HVQM2Header::for_video(32, 16, 2, 2, 4, 33333, 1);
+        let mut encoder = VideoEncoder::new(&header).unwrap();
+        let mut picture = encoder.new_picture();
+        let key = encoder.encode(&picture).unwrap();
+        picture.y.pixels.iter_mut().enumerate().for_each(|(i, pixel)| *pixel = (i % 200) as u8);
+        let predicted = encoder.encode(&picture).unwrap();
+        assert_eq!(predicted.format, DataFormat::VideoPredict);
+
+        let mut decoder = VideoDecoder::new(&header).unwrap();
+        assert!(matches!(decoder.decode(predicted.format, &predicted.data), Err(Error::MissingKeyframe)));
+        assert_eq!(decoder.frames_displayed(), 0);
+
+        decoder.decode(key.format, &key.data).unwrap();
+        assert_eq!(decoder.decode(predicted.format, &predicted.data).unwrap().y.pixels, encoder.picture().y.pixels);
+    }
+
+    #[test]
+    fn hold_needs_a_key_frame() {
+        let header = HVQM2Header::for_video(32, 16, 2, 2, 4, 33333, 1);
+        let mut encoder = VideoEncoder::new(&header).unwrap();
+        assert!(matches!(encoder.hold(), Err(Error::MissingKeyframe)));
+        let mut picture = encoder.new_picture();
+        picture.y.pixels.fill(0x80);
+        let key = encoder.encode(&picture).unwrap();
+
+        let mut decoder = VideoDecoder::new(&header).unwrap();
+        assert!(matches!(decoder.decode(DataFormat::VideoHold, &[]), Err(Error::MissingKeyframe)));
+        assert_eq!(decoder.frames_displayed(), 0);
+
+        decoder.decode(key.format, &key.data).unwrap();
+        assert_eq!(decoder.decode(DataFormat::VideoHold, &[]).unwrap().y.pixels, encoder.picture().y.pixels);
+        assert_eq!(decoder.frames_displayed(), 2);
+    }
+}